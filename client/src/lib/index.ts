@@ -4,6 +4,47 @@ import { PUBLIC_WATSON_API_BASE } from "$env/static/public";
 
 export const axios = axiosLib.create({ baseURL: PUBLIC_WATSON_API_BASE });
 
+// Remember the credentials handed out by `/login`, `/login/mfa` and `/refresh`.
+export function storeSession(data: { session: string; refresh_token: string }) {
+  localStorage.setItem("session", data.session);
+  localStorage.setItem("refresh_token", data.refresh_token);
+  axios.defaults.headers["Authorization"] = data.session;
+}
+
+// When our session expires, swap the refresh token for a new session and retry the request, so
+// that the user isn't sent back to the login page in the middle of revising.
+let refreshing: Promise<string> | null = null;
+axios.interceptors.response.use(undefined, async (error) => {
+  const request = error.config;
+  const refreshToken = localStorage.getItem("refresh_token");
+  if (
+    error.response?.status !== 401 ||
+    request._retried ||
+    refreshToken === null ||
+    request.url === "/login" ||
+    request.url === "/login/mfa" ||
+    request.url === "/refresh"
+  ) {
+    throw error;
+  }
+  request._retried = true;
+
+  refreshing ??= axios
+    .post("/refresh", { refresh_token: refreshToken })
+    .then((res) => {
+      storeSession(res.data);
+      return res.data.session;
+    })
+    .catch((e) => {
+      localStorage.removeItem("session");
+      localStorage.removeItem("refresh_token");
+      throw e;
+    })
+    .finally(() => (refreshing = null));
+  request.headers["Authorization"] = await refreshing;
+  return axios(request);
+});
+
 enum TexString {
   Text,
   Inline,
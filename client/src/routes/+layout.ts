@@ -3,9 +3,8 @@ import { axios } from "$lib";
 
 export const ssr = false;
 
-// Check if we're authenticated. If not, redirect to login page.
-//
-// TODO: Refresh token.
+// Check if we're authenticated. If not, redirect to login page. Expired sessions are refreshed by
+// the axios interceptor in `$lib`.
 export function load({ route }: { route: { id: string } }) {
   let session = localStorage.getItem("session");
   if (session === null && route.id !== "/login" && route.id !== "/register") {
@@ -0,0 +1,7 @@
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    expires TIMESTAMP NOT NULL
+);
+
+CREATE INDEX sessions_expires_idx ON sessions (expires);
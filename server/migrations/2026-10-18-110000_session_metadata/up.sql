@@ -0,0 +1,8 @@
+ALTER TABLE sessions ADD COLUMN public_id UUID NOT NULL DEFAULT uuid_generate_v4();
+ALTER TABLE sessions ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE sessions ADD COLUMN last_seen TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE sessions ADD COLUMN user_agent VARCHAR;
+ALTER TABLE sessions ADD COLUMN ip VARCHAR;
+
+CREATE UNIQUE INDEX sessions_public_id_idx ON sessions (public_id);
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
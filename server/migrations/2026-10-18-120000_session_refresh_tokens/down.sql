@@ -0,0 +1,3 @@
+ALTER TABLE sessions DROP COLUMN absolute_expires;
+ALTER TABLE sessions DROP COLUMN refresh_token_hash;
+ALTER TABLE sessions DROP COLUMN refresh_expires;
@@ -0,0 +1,8 @@
+ALTER TABLE sessions ADD COLUMN absolute_expires TIMESTAMP;
+UPDATE sessions SET absolute_expires = expires;
+ALTER TABLE sessions ALTER COLUMN absolute_expires SET NOT NULL;
+
+ALTER TABLE sessions ADD COLUMN refresh_token_hash VARCHAR;
+ALTER TABLE sessions ADD COLUMN refresh_expires TIMESTAMP;
+
+CREATE UNIQUE INDEX sessions_refresh_token_hash_idx ON sessions (refresh_token_hash);
@@ -0,0 +1,3 @@
+ALTER TABLE users
+ADD COLUMN role VARCHAR NOT NULL DEFAULT 'student'
+CONSTRAINT users_role_check CHECK (role IN ('student', 'moderator', 'admin'));
@@ -0,0 +1,16 @@
+DROP TABLE user_module;
+
+ALTER TABLE users DROP COLUMN cohort;
+
+ALTER TABLE access_tokens ADD COLUMN redeemed BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE access_tokens SET redeemed = redemptions > 0;
+ALTER TABLE access_tokens ALTER COLUMN redeemed DROP DEFAULT;
+
+ALTER TABLE access_tokens DROP COLUMN max_redemptions;
+ALTER TABLE access_tokens DROP COLUMN redemptions;
+ALTER TABLE access_tokens DROP COLUMN expires;
+ALTER TABLE access_tokens DROP COLUMN revoked_at;
+ALTER TABLE access_tokens DROP COLUMN created_at;
+ALTER TABLE access_tokens DROP COLUMN created_by;
+ALTER TABLE access_tokens DROP COLUMN cohort;
+ALTER TABLE access_tokens DROP COLUMN module_id;
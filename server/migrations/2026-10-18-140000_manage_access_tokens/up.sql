@@ -0,0 +1,20 @@
+ALTER TABLE access_tokens ADD COLUMN max_redemptions INT NOT NULL DEFAULT 1;
+ALTER TABLE access_tokens ADD COLUMN redemptions INT NOT NULL DEFAULT 0;
+UPDATE access_tokens SET redemptions = 1 WHERE redeemed;
+ALTER TABLE access_tokens DROP COLUMN redeemed;
+
+ALTER TABLE access_tokens ADD COLUMN expires TIMESTAMP;
+ALTER TABLE access_tokens ADD COLUMN revoked_at TIMESTAMP;
+ALTER TABLE access_tokens ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE access_tokens ADD COLUMN created_by UUID REFERENCES users(id) ON DELETE SET NULL;
+ALTER TABLE access_tokens ADD COLUMN cohort VARCHAR;
+ALTER TABLE access_tokens ADD COLUMN module_id INT REFERENCES modules(id) ON DELETE SET NULL;
+
+ALTER TABLE users ADD COLUMN cohort VARCHAR;
+
+-- Modules a user is enrolled on.
+CREATE TABLE user_module (
+    user_id UUID REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    module_id INT REFERENCES modules(id) ON UPDATE CASCADE ON DELETE CASCADE,
+    CONSTRAINT user_module_pk PRIMARY KEY (user_id, module_id)
+);
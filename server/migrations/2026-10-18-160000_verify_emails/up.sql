@@ -0,0 +1,16 @@
+-- Emails are compared case-insensitively, so store them normalised. This fails if two existing
+-- accounts share an address, which must then be resolved by hand.
+UPDATE users SET email = LOWER(TRIM(email));
+ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE email_verification_tokens (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- The address being verified, which differs from the user's current one when changing email.
+    email VARCHAR NOT NULL,
+    expires TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX email_verification_tokens_user_id_idx ON email_verification_tokens (user_id);
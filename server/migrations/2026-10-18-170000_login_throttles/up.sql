@@ -0,0 +1,12 @@
+-- Failed login attempts, counted both per email address (whether or not an account has it) and per
+-- client IP address.
+CREATE TABLE login_throttles (
+    scope VARCHAR NOT NULL CHECK (scope IN ('email', 'ip')),
+    key VARCHAR NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure TIMESTAMP NOT NULL,
+    locked_until TIMESTAMP,
+    PRIMARY KEY (scope, key)
+);
+
+CREATE INDEX login_throttles_last_failure_idx ON login_throttles (last_failure);
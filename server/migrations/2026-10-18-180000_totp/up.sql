@@ -0,0 +1,20 @@
+-- The base32 TOTP secret, which is set but not yet enabled while the user is enrolling.
+ALTER TABLE users ADD COLUMN totp_secret VARCHAR;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+-- The time step of the last code accepted, so that codes can't be replayed.
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
+
+CREATE TABLE recovery_codes (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR NOT NULL,
+    PRIMARY KEY (user_id, code_hash)
+);
+
+-- Logins which have passed the password check, and are waiting for a second factor.
+CREATE TABLE mfa_challenges (
+    token_hash VARCHAR PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires TIMESTAMP NOT NULL
+);
+
+CREATE INDEX mfa_challenges_user_id_idx ON mfa_challenges (user_id);
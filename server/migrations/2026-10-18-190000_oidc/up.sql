@@ -0,0 +1,20 @@
+-- OpenID Connect logins which have been sent to the provider, and are waiting for it to redirect
+-- back.
+CREATE TABLE oidc_logins (
+    state_hash VARCHAR PRIMARY KEY,
+    nonce VARCHAR NOT NULL,
+    code_verifier VARCHAR NOT NULL,
+    cookie BOOLEAN NOT NULL,
+    expires TIMESTAMP NOT NULL
+);
+
+-- Accounts at OpenID Connect providers which users can log in with.
+CREATE TABLE user_identities (
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX user_identities_user_id_idx ON user_identities (user_id);
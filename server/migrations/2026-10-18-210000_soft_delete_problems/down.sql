@@ -0,0 +1 @@
+ALTER TABLE problems DROP COLUMN deleted_at;
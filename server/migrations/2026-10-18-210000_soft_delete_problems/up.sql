@@ -0,0 +1,2 @@
+-- Deleted problems are kept, so that they can be restored, but are no longer shown to anyone.
+ALTER TABLE problems ADD COLUMN deleted_at TIMESTAMP;
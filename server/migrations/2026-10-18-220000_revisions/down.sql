@@ -0,0 +1,3 @@
+DROP TABLE solution_revisions;
+DROP TABLE problem_revisions;
+DROP FUNCTION forbid_revision_changes;
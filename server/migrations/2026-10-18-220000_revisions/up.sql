@@ -0,0 +1,41 @@
+-- Every version of a problem or solution, from when it was created. A revision's previous content is
+-- the revision before it, so nothing is lost when one is edited.
+CREATE TABLE problem_revisions (
+    problem_id INTEGER NOT NULL REFERENCES problems(id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    body TEXT,
+    author VARCHAR,
+    source VARCHAR,
+    solnlink VARCHAR,
+    img_path VARCHAR,
+    edited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    edited_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (problem_id, revision)
+);
+
+CREATE TABLE solution_revisions (
+    solution_id INTEGER NOT NULL REFERENCES solutions(id) ON DELETE CASCADE,
+    revision INTEGER NOT NULL,
+    body TEXT,
+    img_path VARCHAR,
+    edited_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    edited_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (solution_id, revision)
+);
+
+-- Revisions are only ever added, or removed along with what they belong to.
+CREATE FUNCTION forbid_revision_changes() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'revisions cannot be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER problem_revisions_immutable BEFORE UPDATE ON problem_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
+CREATE TRIGGER solution_revisions_immutable BEFORE UPDATE ON solution_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
+
+INSERT INTO problem_revisions (problem_id, revision, body, author, source, solnlink, img_path, edited_by, edited_at)
+    SELECT id, 1, body, author, source, solnlink, img_path, user_id, submitted_at FROM problems;
+INSERT INTO solution_revisions (solution_id, revision, body, img_path, edited_by, edited_at)
+    SELECT id, 1, body, img_path, user_id, submitted_at FROM solutions;
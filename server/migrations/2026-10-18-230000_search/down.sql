@@ -0,0 +1,9 @@
+DROP TRIGGER solutions_index ON solutions;
+DROP FUNCTION index_solution_problem;
+DROP TRIGGER problems_index ON problems;
+DROP FUNCTION index_problem;
+ALTER TABLE problems DROP COLUMN search;
+DROP FUNCTION search_headline;
+DROP FUNCTION search_query;
+DROP FUNCTION problem_search_vector;
+DROP FUNCTION latex_to_text;
@@ -0,0 +1,59 @@
+-- LaTeX, reduced to the words in it for indexing: environments and commands such as `\frac` are
+-- dropped, along with the symbols used in markup, while the text in their arguments is kept.
+CREATE FUNCTION latex_to_text(latex TEXT) RETURNS TEXT AS $$
+    SELECT regexp_replace(
+        regexp_replace(
+            regexp_replace(latex, '\\(begin|end)\{[^}]*\}', ' ', 'g'),
+            '\\[a-zA-Z]+\*?', ' ', 'g'),
+        '[\\$&^_{}]', ' ', 'g')
+$$ LANGUAGE sql IMMUTABLE;
+
+-- What a problem is found by: its body first, then its source, then its solutions.
+CREATE FUNCTION problem_search_vector(body TEXT, source TEXT, problem_id INTEGER) RETURNS TSVECTOR AS $$
+    SELECT setweight(to_tsvector('english', latex_to_text(coalesce(body, ''))), 'A')
+        || setweight(to_tsvector('english', coalesce(source, '')), 'B')
+        || setweight(to_tsvector('english', latex_to_text(coalesce(
+            (SELECT string_agg(solutions.body, ' ') FROM solutions WHERE solutions.problem_id = $3),
+            ''))), 'C')
+$$ LANGUAGE sql STABLE;
+
+-- Searches are written as for a web search engine, e.g. `cauchy schwarz -integral`.
+CREATE FUNCTION search_query(query TEXT) RETURNS TSQUERY AS $$
+    SELECT websearch_to_tsquery('english', query)
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Fragments of a document around what matched a search, with the matches marked.
+CREATE FUNCTION search_headline(document TEXT, query TSQUERY) RETURNS TEXT AS $$
+    SELECT ts_headline('english', document, query,
+        'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=30, MinWords=10')
+$$ LANGUAGE sql IMMUTABLE;
+
+ALTER TABLE problems ADD COLUMN search TSVECTOR NOT NULL DEFAULT '';
+CREATE INDEX problems_search_idx ON problems USING GIN (search);
+
+CREATE FUNCTION index_problem() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search := problem_search_vector(NEW.body, NEW.source, NEW.id);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER problems_index BEFORE INSERT OR UPDATE OF body, source ON problems
+    FOR EACH ROW EXECUTE FUNCTION index_problem();
+
+-- Only `search` is set here, so this doesn't set off `problems_index`.
+CREATE FUNCTION index_solution_problem() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE problems SET search = problem_search_vector(body, source, id)
+        WHERE id IN (
+            CASE WHEN TG_OP = 'DELETE' THEN NULL ELSE NEW.problem_id END,
+            CASE WHEN TG_OP = 'INSERT' THEN NULL ELSE OLD.problem_id END
+        );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER solutions_index AFTER INSERT OR UPDATE OF body, problem_id OR DELETE ON solutions
+    FOR EACH ROW EXECUTE FUNCTION index_solution_problem();
+
+UPDATE problems SET search = problem_search_vector(body, source, id);
@@ -0,0 +1,10 @@
+-- Which IP addresses failed to log in as which email addresses, so that unlocking an account can
+-- also lift lockouts of IP addresses which only ever failed against it.
+CREATE TABLE login_failure_sources (
+    email VARCHAR NOT NULL,
+    ip VARCHAR NOT NULL,
+    last_failure TIMESTAMP NOT NULL,
+    PRIMARY KEY (email, ip)
+);
+
+CREATE INDEX login_failure_sources_ip_idx ON login_failure_sources (ip);
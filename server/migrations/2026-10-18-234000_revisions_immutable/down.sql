@@ -0,0 +1,12 @@
+DROP TRIGGER problem_revisions_immutable ON problem_revisions;
+DROP TRIGGER solution_revisions_immutable ON solution_revisions;
+CREATE TRIGGER problem_revisions_immutable BEFORE UPDATE ON problem_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
+CREATE TRIGGER solution_revisions_immutable BEFORE UPDATE ON solution_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
+
+CREATE OR REPLACE FUNCTION forbid_revision_changes() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'revisions cannot be changed';
+END;
+$$ LANGUAGE plpgsql;
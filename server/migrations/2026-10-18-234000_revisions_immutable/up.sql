@@ -0,0 +1,26 @@
+-- Revisions can't be deleted either, except along with what they belong to, and who made one can
+-- only be forgotten when their account is deleted.
+CREATE OR REPLACE FUNCTION forbid_revision_changes() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        IF NEW.edited_by IS NULL AND to_jsonb(NEW) - 'edited_by' = to_jsonb(OLD) - 'edited_by' THEN
+            RETURN NEW;
+        END IF;
+    -- By the time the deletion cascades here, the problem or solution itself is gone.
+    ELSIF TG_TABLE_NAME = 'problem_revisions' THEN
+        IF NOT EXISTS (SELECT FROM problems WHERE id = OLD.problem_id) THEN
+            RETURN OLD;
+        END IF;
+    ELSIF NOT EXISTS (SELECT FROM solutions WHERE id = OLD.solution_id) THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'revisions cannot be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER problem_revisions_immutable ON problem_revisions;
+DROP TRIGGER solution_revisions_immutable ON solution_revisions;
+CREATE TRIGGER problem_revisions_immutable BEFORE UPDATE OR DELETE ON problem_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
+CREATE TRIGGER solution_revisions_immutable BEFORE UPDATE OR DELETE ON solution_revisions
+    FOR EACH ROW EXECUTE FUNCTION forbid_revision_changes();
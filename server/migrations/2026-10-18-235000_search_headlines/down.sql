@@ -0,0 +1,7 @@
+DROP FUNCTION search_headline;
+CREATE FUNCTION search_headline(document TEXT, query TSQUERY) RETURNS TEXT AS $$
+    SELECT ts_headline('english', document, query,
+        'StartSel=<mark>, StopSel=</mark>, MaxFragments=2, MaxWords=30, MinWords=10')
+$$ LANGUAGE sql IMMUTABLE;
+
+DROP FUNCTION escape_html;
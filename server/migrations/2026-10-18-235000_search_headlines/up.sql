@@ -0,0 +1,28 @@
+-- Text made safe to put in HTML.
+CREATE FUNCTION escape_html(text TEXT) RETURNS TEXT AS $$
+    SELECT replace(replace(replace(replace(replace(text,
+        '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;')
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Fragments of a problem around what matched a search, with the matches in `<mark>` tags and
+-- everything else escaped. They are taken from the body, the source or a solution, whichever
+-- matched first, as indexed, falling back to the body.
+DROP FUNCTION search_headline;
+CREATE FUNCTION search_headline(body TEXT, source TEXT, problem_id INTEGER, query TSQUERY)
+RETURNS TEXT AS $$
+    -- Matches are marked with control characters, which can't be mistaken for HTML, so that they
+    -- survive escaping.
+    SELECT replace(replace(escape_html(ts_headline('english', document, query,
+            'StartSel=' || chr(2) || ', StopSel=' || chr(3)
+                || ', MaxFragments=2, MaxWords=30, MinWords=10')),
+        chr(2), '<mark>'), chr(3), '</mark>')
+    FROM (
+        SELECT latex_to_text(body) AS document, 1 AS priority, NULL::INTEGER AS solution_id
+        UNION ALL SELECT source, 2, NULL
+        UNION ALL SELECT latex_to_text(solutions.body), 3, solutions.id
+            FROM solutions WHERE solutions.problem_id = $3
+    ) documents
+    WHERE document IS NOT NULL
+    ORDER BY to_tsvector('english', document) @@ query DESC, priority, solution_id
+    LIMIT 1
+$$ LANGUAGE sql STABLE;
@@ -0,0 +1,193 @@
+use axum::{extract::State, http::HeaderMap};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    db::{Db, DbError},
+    error::AppError,
+    extract::{Json, Path},
+    extract_user_id,
+    models::{AccessToken, AccessTokenStatus, Role},
+    throttle::{LoginThrottle, Unlocked},
+};
+
+#[derive(Deserialize)]
+pub struct SetRole {
+    role: Role,
+}
+
+/// Change the role of a user.
+pub async fn set_role(
+    State(db): State<Db>,
+    Path(user_id): Path<Uuid>,
+    Json(SetRole { role }): Json<SetRole>,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+    db.run(move |conn| {
+        let updated = diesel::update(users::table.find(user_id))
+            .set(users::role.eq(role))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(AppError::NotFound("User not found".to_string()));
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Clear a user's failed login attempts, ending any lockout, and say what was lifted.
+pub async fn unlock_user(
+    State(db): State<Db>,
+    State(throttle): State<LoginThrottle>,
+    Path(user_id): Path<Uuid>,
+) -> Result<Json<Unlocked>, AppError> {
+    use crate::schema::users;
+    let email: String = db
+        .run(move |conn| {
+            users::table
+                .find(user_id)
+                .select(users::email)
+                .first(conn)
+                .optional()
+                .map_err(DbError::from)
+        })
+        .await?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))?;
+    Ok(Json(throttle.unlock(&email).await?))
+}
+
+#[derive(Deserialize)]
+pub struct NewAccessToken {
+    /// Display name of the user registering. For tokens with several uses, users may choose their
+    /// own name when registering instead.
+    pub name: String,
+    #[serde(default = "NewAccessToken::default_max_redemptions")]
+    pub max_redemptions: i32,
+    pub expires: Option<NaiveDateTime>,
+    pub cohort: Option<String>,
+    pub module_id: Option<i32>,
+}
+
+impl NewAccessToken {
+    fn default_max_redemptions() -> i32 {
+        1
+    }
+}
+
+/// Issue a new access token, which can be used to register.
+pub async fn create_token(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Json(new_token): Json<NewAccessToken>,
+) -> Result<Json<AccessToken>, AppError> {
+    use crate::schema::modules;
+    let user_id = extract_user_id(&headers)?;
+
+    if new_token.max_redemptions < 1 {
+        return Err(AppError::BadRequest(
+            "A token must be redeemable at least once".to_string(),
+        ));
+    }
+    db.run(move |conn| {
+        if let Some(module_id) = new_token.module_id {
+            let module_exists: bool =
+                diesel::select(diesel::dsl::exists(modules::table.find(module_id)))
+                    .get_result(conn)?;
+            if !module_exists {
+                return Err(AppError::BadRequest("No such module".to_string()));
+            }
+        }
+
+        let token = insert_token(conn, Some(user_id), new_token)?;
+
+        Ok(Json(token))
+    })
+    .await
+}
+
+pub fn insert_token(
+    conn: &mut PgConnection,
+    created_by: Option<Uuid>,
+    new_token: NewAccessToken,
+) -> QueryResult<AccessToken> {
+    use crate::schema::access_tokens;
+    diesel::insert_into(access_tokens::table)
+        .values((
+            access_tokens::id.eq(Uuid::new_v4()),
+            access_tokens::name.eq(new_token.name),
+            access_tokens::max_redemptions.eq(new_token.max_redemptions),
+            access_tokens::expires.eq(new_token.expires),
+            access_tokens::created_at.eq(Utc::now().naive_utc()),
+            access_tokens::created_by.eq(created_by),
+            access_tokens::cohort.eq(new_token.cohort),
+            access_tokens::module_id.eq(new_token.module_id),
+        ))
+        .returning(AccessToken::as_returning())
+        .get_result(conn)
+}
+
+#[derive(Serialize)]
+pub struct AccessTokenView {
+    #[serde(flatten)]
+    token: AccessToken,
+    status: AccessTokenStatus,
+}
+
+/// List every access token, newest first.
+pub async fn list_tokens(State(db): State<Db>) -> Result<Json<Vec<AccessTokenView>>, AppError> {
+    use crate::schema::access_tokens;
+    db.run(move |conn| {
+        let tokens = access_tokens::table
+            .order(access_tokens::created_at.desc())
+            .select(AccessToken::as_select())
+            .load(conn)?;
+
+        let now = Utc::now().naive_utc();
+        Ok(Json(
+            tokens
+                .into_iter()
+                .map(|token| AccessTokenView {
+                    status: token.status(now),
+                    token,
+                })
+                .collect(),
+        ))
+    })
+    .await
+}
+
+/// Revoke an access token, so that its remaining uses can no longer be redeemed.
+pub async fn revoke_token(
+    State(db): State<Db>,
+    Path(token_id): Path<Uuid>,
+) -> Result<(), AppError> {
+    use crate::schema::access_tokens;
+    db.run(move |conn| {
+        let token = access_tokens::table
+            .find(token_id)
+            .select(AccessToken::as_select())
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("Token not found".to_string()))?;
+
+        match token.status(Utc::now().naive_utc()) {
+            AccessTokenStatus::Active | AccessTokenStatus::Expired => {}
+            AccessTokenStatus::Redeemed => {
+                return Err(AppError::Conflict(
+                    "Token has already been fully redeemed".to_string(),
+                ))
+            }
+            AccessTokenStatus::Revoked => {
+                return Err(AppError::Conflict("Token already revoked".to_string()))
+            }
+        }
+
+        diesel::update(access_tokens::table.find(token_id))
+            .set(access_tokens::revoked_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,156 @@
+use axum::{extract::State, http::HeaderMap};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::extract_user_role,
+    db::{Db, DbError, DbResult},
+    error::AppError,
+    extract::{Json, Path},
+    extract_user_id,
+    models::{ApiKey, ApiKeyScope, Role},
+    token,
+};
+
+/// Prefix of every API key, which tells them apart from session ids and makes them easy to spot
+/// if they are leaked.
+pub const PREFIX: &str = "wat_";
+
+/// `last_used` is only updated when it is at least this many seconds out of date, to avoid
+/// writing to the database on every request.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// Look up an unrevoked API key, returning it along with its owner's role and recording that it
+/// was used.
+pub async fn authenticate(db: &Db, key: &str) -> DbResult<Option<(ApiKey, Role)>> {
+    use crate::schema::{api_keys, users};
+    let key_hash = token::hash(key);
+    db.run(move |conn| {
+        let Some((api_key, role)) = api_keys::table
+            .inner_join(users::table)
+            .filter(api_keys::key_hash.eq(key_hash))
+            .filter(api_keys::revoked_at.is_null())
+            .select((ApiKey::as_select(), users::role))
+            .first::<(ApiKey, Role)>(conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let now = Utc::now().naive_utc();
+        if api_key.last_used.map_or(true, |last_used| {
+            (now - last_used).num_seconds() >= LAST_USED_RESOLUTION
+        }) {
+            diesel::update(api_keys::table.find(api_key.id))
+                .set(api_keys::last_used.eq(now))
+                .execute(conn)?;
+        }
+        Ok(Some((api_key, role)))
+    })
+    .await
+}
+
+/// List the API keys of the user making the request, including revoked ones.
+pub async fn list_keys(
+    State(db): State<Db>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ApiKey>>, AppError> {
+    use crate::schema::api_keys;
+    let user_id = extract_user_id(&headers)?;
+    let keys = db
+        .run(move |conn| {
+            api_keys::table
+                .filter(api_keys::user_id.eq(user_id))
+                .order(api_keys::created_at.desc())
+                .select(ApiKey::as_select())
+                .load(conn)
+                .map_err(DbError::from)
+        })
+        .await?;
+    Ok(Json(keys))
+}
+
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    name: String,
+    scope: ApiKeyScope,
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    /// The key itself. Only its hash is stored, so this is the only time it is shown.
+    key: String,
+}
+
+/// Create an API key for the user making the request.
+pub async fn create_key(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Json(NewApiKey { name, scope }): Json<NewApiKey>,
+) -> Result<Json<CreatedApiKey>, AppError> {
+    use crate::schema::api_keys;
+    let user_id = extract_user_id(&headers)?;
+    if name.trim().is_empty() {
+        return Err(AppError::BadRequest(
+            "API key name must not be empty".to_string(),
+        ));
+    }
+    if scope == ApiKeyScope::Admin && extract_user_role(&headers)? < Role::Admin {
+        return Err(AppError::Forbidden(
+            "Only admins can create admin API keys".to_string(),
+        ));
+    }
+
+    let key = format!("{PREFIX}{}", token::generate());
+    let key_hash = token::hash(&key);
+    let api_key = db
+        .run(move |conn| {
+            diesel::insert_into(api_keys::table)
+                .values((
+                    api_keys::id.eq(Uuid::new_v4()),
+                    api_keys::user_id.eq(user_id),
+                    api_keys::name.eq(name.trim()),
+                    api_keys::key_hash.eq(key_hash),
+                    api_keys::scope.eq(scope),
+                    api_keys::created_at.eq(Utc::now().naive_utc()),
+                ))
+                .returning(ApiKey::as_returning())
+                .get_result(conn)
+                .map_err(DbError::from)
+        })
+        .await?;
+
+    Ok(Json(CreatedApiKey { api_key, key }))
+}
+
+/// Revoke one of the requesting user's API keys, so that it can no longer be used.
+pub async fn revoke_key(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(key_id): Path<Uuid>,
+) -> Result<(), AppError> {
+    use crate::schema::api_keys;
+    let user_id = extract_user_id(&headers)?;
+    db.run(move |conn| {
+        let revoked_at: Option<NaiveDateTime> = api_keys::table
+            .find(key_id)
+            .filter(api_keys::user_id.eq(user_id))
+            .select(api_keys::revoked_at)
+            .first(conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFound("API key not found".to_string()))?;
+        if revoked_at.is_some() {
+            return Err(AppError::Conflict("API key already revoked".to_string()));
+        }
+
+        diesel::update(api_keys::table.find(key_id))
+            .set(api_keys::revoked_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        Ok(())
+    })
+    .await
+}
@@ -1,174 +1,659 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{Arc, OnceLock};
 
 use argon2::{
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::{header::AUTHORIZATION, HeaderMap},
     middleware::Next,
-    response::{Json, Response},
+    response::{IntoResponse, Response},
 };
-use chrono::{offset::Utc, DateTime, Days};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use chrono::{offset::Utc, NaiveDateTime};
 use diesel::prelude::*;
 use rand::rngs::OsRng;
-use serde::Deserialize;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
-use crate::{establish_connection, internal_error, models::AccessToken};
-
-#[derive(Clone)]
-pub struct Session {
-    user_id: Uuid,
-    expires: DateTime<Utc>,
-}
+use crate::{
+    api_key, config,
+    db::{Db, DbError, DbResult},
+    email::{email_in_use, normalise_email, send_verification, validate_email},
+    error::{internal_error, AppError},
+    extract::{Json, Path},
+    extract_user_id, logging,
+    mail::Mailer,
+    mfa,
+    models::{AccessToken, AccessTokenStatus, ApiKeyScope, Role, UserModule},
+    session::{ClientInfo, IssuedSession, Sessions},
+    throttle::LoginThrottle,
+    token,
+};
 
-pub type Sessions = Arc<RwLock<HashMap<Uuid, Session>>>;
+const SESSION_COOKIE: &str = "session";
+const REFRESH_COOKIE: &str = "refresh_token";
+const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Set by [`auth`] on requests authenticated with an API key.
+const API_KEY_SCOPE_HEADER: &str = "api_key_scope";
 
-/// Middleware for session authentication.
+/// Middleware for session or API key authentication.
+///
+/// The session id is taken from the `Authorization` header if present, or otherwise from the
+/// session cookie. Cookies are sent by the browser automatically, so requests authenticated by
+/// cookie which may change state must also carry the CSRF token in the `X-CSRF-Token` header.
+///
+/// An API key may be given in the `Authorization` header instead, optionally after `Bearer `.
 pub async fn auth(
+    State(db): State<Db>,
     State(sessions): State<Sessions>,
+    jar: CookieJar,
     mut request: Request,
     next: Next,
-) -> Result<Response, (StatusCode, String)> {
-    let session_id = request
+) -> Result<Response, AppError> {
+    // These are only set below, so any sent by the client must not get through.
+    request.headers_mut().remove("session_id");
+    request.headers_mut().remove(API_KEY_SCOPE_HEADER);
+
+    let api_key = request
         .headers()
-        .get("Authorization")
-        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "No session active".to_string()))?
-        .to_str()
-        .map_err(internal_error)?;
-
-    let sessions_rlock = sessions.read().await;
-    let session = sessions_rlock
-        .get(&Uuid::parse_str(session_id).map_err(internal_error)?)
-        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "No session active".to_string()))?;
-
-    if Utc::now()
-        .signed_duration_since(session.expires)
-        .num_microseconds()
-        .unwrap_or(1)
-        > 0
-    {
-        return Err((StatusCode::UNAUTHORIZED, "Session expired".to_string()));
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.strip_prefix("Bearer ").unwrap_or(header))
+        .filter(|header| header.starts_with(api_key::PREFIX))
+        .map(str::to_string);
+    if let Some(api_key) = api_key {
+        return api_key_auth(&db, &api_key, request, next).await;
     }
 
+    let no_session = || AppError::Unauthorized("No session active".to_string());
+    let session_id = match request.headers().get(AUTHORIZATION) {
+        Some(header) => header.to_str().map_err(|_| no_session())?.to_string(),
+        None => {
+            let cookie = jar.get(SESSION_COOKIE).ok_or_else(no_session)?;
+            if !request.method().is_safe() {
+                check_csrf(&jar, request.headers())?;
+            }
+            cookie.value().to_string()
+        }
+    };
+
+    let session_id = Uuid::parse_str(&session_id).map_err(|_| no_session())?;
+    let (session, role) = sessions.get(session_id).await?.ok_or_else(no_session)?;
+
+    if Utc::now().naive_utc() > session.expires {
+        return Err(AppError::Unauthorized("Session expired".to_string()));
+    }
+
+    sessions.touch(&session).await?;
+
     // Ok, we are authorized!
+    logging::record_user_id(session.user_id);
     request
         .headers_mut()
         .insert("user_id", session.user_id.to_string().parse().unwrap());
+    request
+        .headers_mut()
+        .insert("session_id", session.id.to_string().parse().unwrap());
+    request
+        .headers_mut()
+        .insert("user_role", role.as_str().parse().unwrap());
+
+    Ok(next.run(request).await)
+}
+
+/// Authenticate a request made with an API key. The request acts with the key owner's role, limited
+/// by the key's scope.
+async fn api_key_auth(
+    db: &Db,
+    key: &str,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let (api_key, role) = api_key::authenticate(db, key)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("Invalid API key".to_string()))?;
 
+    if api_key.scope == ApiKeyScope::Read && !request.method().is_safe() {
+        return Err(AppError::Forbidden("This API key is read-only".to_string()));
+    }
+
+    logging::record_user_id(api_key.user_id);
+    request
+        .headers_mut()
+        .insert("user_id", api_key.user_id.to_string().parse().unwrap());
+    request.headers_mut().insert(
+        "user_role",
+        api_key.scope.effective_role(role).as_str().parse().unwrap(),
+    );
+    request.headers_mut().insert(
+        API_KEY_SCOPE_HEADER,
+        api_key.scope.as_str().parse().unwrap(),
+    );
+
+    Ok(next.run(request).await)
+}
+
+/// Middleware rejecting requests authenticated with an API key, for endpoints which manage the
+/// account itself, so that a leaked key can't be used to take it over. It must run after [`auth`].
+pub async fn require_session(request: Request, next: Next) -> Result<Response, AppError> {
+    if request.headers().contains_key(API_KEY_SCOPE_HEADER) {
+        return Err(AppError::Forbidden(
+            "This can't be done with an API key".to_string(),
+        ));
+    }
     Ok(next.run(request).await)
 }
 
+/// Middleware rejecting users below the role given as its state, e.g.
+/// `middleware::from_fn_with_state(Role::Admin, require_role)`. It must run after [`auth`].
+pub async fn require_role(
+    State(min_role): State<Role>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if extract_user_role(request.headers())? < min_role {
+        return Err(AppError::Forbidden(format!(
+            "This requires the {min_role} role"
+        )));
+    }
+    Ok(next.run(request).await)
+}
+
+pub fn extract_user_role(headers: &HeaderMap) -> Result<Role, AppError> {
+    headers
+        .get("user_role")
+        .and_then(|role| role.to_str().ok())
+        .and_then(|role| role.parse().ok())
+        .ok_or_else(|| AppError::Unauthorized("No user".to_string()))
+}
+
+/// Double-submit CSRF check: the token in the header must match the one in the cookie, which a
+/// cross-site attacker can neither read nor set.
+fn check_csrf(jar: &CookieJar, headers: &HeaderMap) -> Result<(), AppError> {
+    let invalid = || AppError::Forbidden("Missing or invalid CSRF token".to_string());
+    let cookie = jar.get(CSRF_COOKIE).ok_or_else(invalid)?;
+    let header = headers.get(CSRF_HEADER).ok_or_else(invalid)?;
+    if !bool::from(cookie.value().as_bytes().ct_eq(header.as_bytes())) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Response body for a session handed out in cookies. The CSRF token is included so that clients
+/// served from a different origin than the API, which can't read the cookie, can still send it.
+#[derive(Serialize)]
+pub struct CookieSession {
+    pub expires: NaiveDateTime,
+    pub csrf_token: String,
+}
+
+/// Hand a newly issued session to the client, either in the response body or in cookies.
+pub fn session_response(
+    sessions: &Sessions,
+    jar: CookieJar,
+    issued: IssuedSession,
+    use_cookies: bool,
+) -> Response {
+    if !use_cookies {
+        return Json(issued).into_response();
+    }
+
+    let (jar, session) = set_session_cookies(sessions, jar, issued);
+    (jar, Json(session)).into_response()
+}
+
+/// Put a newly issued session in cookies, returning what the client needs to know about it.
+pub fn set_session_cookies(
+    sessions: &Sessions,
+    jar: CookieJar,
+    issued: IssuedSession,
+) -> (CookieJar, CookieSession) {
+    let cookie = |name, value| {
+        Cookie::build((name, value))
+            .path("/")
+            .secure(true)
+            .same_site(SameSite::Strict)
+    };
+    let refresh_max_age = time::Duration::seconds(sessions.config().refresh_timeout.num_seconds());
+    let csrf_token = token::generate();
+    let jar = jar
+        .add(cookie(SESSION_COOKIE, issued.session.to_string()).http_only(true))
+        .add(
+            cookie(REFRESH_COOKIE, issued.refresh_token)
+                .http_only(true)
+                .max_age(refresh_max_age),
+        )
+        .add(cookie(CSRF_COOKIE, csrf_token.clone()).max_age(refresh_max_age));
+
+    (
+        jar,
+        CookieSession {
+            expires: issued.expires,
+            csrf_token,
+        },
+    )
+}
+
+/// Argon2 parameters for hashing new passwords, defaulting to those of the `argon2` crate.
+///
+/// Existing hashes keep working when these change, and are rehashed when their users next log in.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl Argon2Config {
+    pub fn params(&self) -> argon2::Result<Params> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+    }
+}
+
+/// The configured Argon2 parameters.
+pub fn password_params() -> &'static Params {
+    static PARAMS: OnceLock<Params> = OnceLock::new();
+    PARAMS.get_or_init(|| {
+        config::get()
+            .argon2
+            .params()
+            .unwrap_or_else(|e| panic!("Invalid Argon2 parameters: {e}"))
+    })
+}
+
+fn password_hasher() -> Argon2<'static> {
+    Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        password_params().clone(),
+    )
+}
+
+/// Hash a password for storage in the `users` table.
+pub fn hash_password(password: &str) -> argon2::password_hash::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(password_hasher()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Whether a password hash was made with other parameters than those currently configured, so
+/// should be replaced while we know the password.
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    let Ok(params) = Params::try_from(&hash) else {
+        return false;
+    };
+    let current = password_params();
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost()
+}
+
+/// A hash to check passwords against when logging in as someone without one, so that it takes as
+/// long as it would otherwise.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("").expect("Couldn't hash password"))
+}
+
+/// Check a password against a hash from the `users` table.
+pub fn verify_password(password: &str, hash: &str) -> argon2::password_hash::Result<bool> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    // The parameters are taken from the hash, so this works whatever they were.
+    Ok(password_hasher()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
+/// Replace a user's password hash with one using the current parameters. This is only an upgrade,
+/// so failures are logged rather than returned.
+fn rehash_password(conn: &mut PgConnection, user_id: Uuid, password: &str) {
+    use crate::schema::users;
+    let result = hash_password(password)
+        .map_err(|e| e.to_string())
+        .and_then(|hash| {
+            diesel::update(users::table.find(user_id))
+                .set(users::password.eq(hash))
+                .execute(conn)
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = result {
+        tracing::error!("Failed to rehash password: {e}");
+    }
+}
+
+/// Check the password of a signed-in user, for confirming sensitive changes to their account.
+/// Wrong passwords count as failed logins, so that a stolen session can't be used to guess it.
+pub async fn confirm_password(
+    db: &Db,
+    throttle: &LoginThrottle,
+    client: &ClientInfo,
+    user_id: Uuid,
+    password: String,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+    let (email, password_hash): (String, Option<String>) = db
+        .run(move |conn| {
+            users::table
+                .find(user_id)
+                .select((users::email, users::password.nullable()))
+                .first(conn)
+                .map_err(DbError::from)
+        })
+        .await?;
+
+    if let Some(wait) = throttle.locked_for(&email, client.ip.as_deref()).await? {
+        return Err(AppError::TooManyAttempts(wait));
+    }
+    // Accounts made through single sign-on have no password until one is set by a reset.
+    let Some(password_hash) = password_hash else {
+        return Err(AppError::Forbidden(
+            "This account has no password. Set one with a password reset first.".to_string(),
+        ));
+    };
+    let password_correct = verify_password(&password, &password_hash)
+        .map_err(|_| AppError::Internal("Internal error occurred".to_string()))?;
+    if !password_correct {
+        throttle
+            .record_failure(&email, client.ip.as_deref())
+            .await?;
+        return Err(AppError::Unauthorized("Incorrect password.".to_string()));
+    }
+    throttle.reset(&email).await?;
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct RegisterRequestBody {
     req_token: Uuid,
     req_email: String,
     req_password: String,
+    /// Display name, for tokens shared by several users. Defaults to the name on the token.
+    req_name: Option<String>,
 }
 
 pub async fn register(
+    State(db): State<Db>,
+    State(mailer): State<Arc<dyn Mailer>>,
     Json(RegisterRequestBody {
         req_token,
         req_email,
         req_password,
+        req_name,
     }): Json<RegisterRequestBody>,
-) -> Result<(), (StatusCode, String)> {
-    use crate::schema::{access_tokens, users};
-    let mut conn = establish_connection();
-    let token = access_tokens::table::find(access_tokens::table, req_token)
-        .select(AccessToken::as_select())
-        .first(&mut conn)
-        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token.".to_string()))?;
-    if token.redeemed {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "Token already redeemed.".to_string(),
-        ));
-    }
+) -> Result<(), AppError> {
+    use crate::schema::{access_tokens, user_module, users};
+    let req_email = validate_email(&req_email)?;
 
-    // Wooo! New user!
-    diesel::update(access_tokens::table.find(req_token))
-        .set(access_tokens::redeemed.eq(true))
-        .execute(&mut conn)
-        .map_err(internal_error)?;
+    db.run(move |conn| {
+        let token = access_tokens::table::find(access_tokens::table, req_token)
+            .select(AccessToken::as_select())
+            .first(conn)
+            .map_err(|_| AppError::Unauthorized("Invalid token.".to_string()))?;
+        let token_rejected = |message: &str| Err(AppError::Unauthorized(message.to_string()));
+        match token.status(Utc::now().naive_utc()) {
+            AccessTokenStatus::Active => {}
+            AccessTokenStatus::Redeemed => return token_rejected("Token already redeemed."),
+            AccessTokenStatus::Expired => return token_rejected("Token expired."),
+            AccessTokenStatus::Revoked => return token_rejected("Token revoked."),
+        }
 
-    let salt = SaltString::generate(&mut OsRng);
-    let hashed_password = Argon2::default()
-        .hash_password(req_password.as_bytes(), &salt)
-        .map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Couldn't hash password".to_string(),
-            )
-        })?
-        .to_string();
-
-    diesel::insert_into(users::table)
-        .values((
-            users::name.eq(&token.name),
-            users::email.eq(&req_email),
-            users::password.eq(&hashed_password),
-        ))
-        .execute(&mut conn)
-        .map_err(internal_error)?;
-    Ok(())
+        let hashed_password = hash_password(&req_password)
+            .map_err(|_| AppError::Internal("Couldn't hash password".to_string()))?;
+
+        // Wooo! New user! Claim a redemption and create the user together, so that neither happens
+        // without the other. The token may have been used up since we checked it above, hence the
+        // filter on the number of redemptions.
+        let registered = conn
+            .transaction(|conn| {
+                let claimed = diesel::update(
+                    access_tokens::table
+                        .find(req_token)
+                        .filter(access_tokens::redemptions.lt(access_tokens::max_redemptions)),
+                )
+                .set(access_tokens::redemptions.eq(access_tokens::redemptions + 1))
+                .execute(conn)?;
+                if claimed == 0 {
+                    return Ok(None);
+                }
+
+                let user_id: Uuid = diesel::insert_into(users::table)
+                    .values((
+                        users::name.eq(req_name.as_ref().unwrap_or(&token.name)),
+                        users::email.eq(&req_email),
+                        users::password.eq(&hashed_password),
+                        users::cohort.eq(&token.cohort),
+                    ))
+                    .returning(users::id)
+                    .get_result(conn)?;
+
+                if let Some(module_id) = token.module_id {
+                    diesel::insert_into(user_module::table)
+                        .values(UserModule { user_id, module_id })
+                        .execute(conn)?;
+                }
+                diesel::QueryResult::Ok(Some(user_id))
+            })
+            .map_err(email_in_use)?;
+
+        let Some(user_id) = registered else {
+            return token_rejected("Token already redeemed.");
+        };
+        send_verification(conn, mailer, user_id, req_email).map_err(internal_error)
+    })
+    .await
 }
 
 #[derive(Deserialize)]
 pub struct AuthRequestBody {
     req_email: String,
     req_password: String,
+    /// Whether to hand out the session in cookies rather than in the response body.
+    #[serde(default)]
+    cookie: bool,
 }
 
 pub async fn login(
+    State(db): State<Db>,
     State(sessions): State<Sessions>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    jar: CookieJar,
     Json(AuthRequestBody {
         req_email,
         req_password,
+        cookie,
     }): Json<AuthRequestBody>,
-) -> Result<String, (StatusCode, String)> {
+) -> Result<Response, AppError> {
     use crate::schema::users::*;
-    let mut conn = establish_connection();
+    let req_email = normalise_email(&req_email);
+
+    if let Some(wait) = throttle
+        .locked_for(&req_email, client.ip.as_deref())
+        .await?
+    {
+        return Err(AppError::TooManyAttempts(wait));
+    }
 
-    let (user_id, password_hash): (Uuid, Option<String>) =
-        table::filter(table, email.eq(req_email))
-            .select((id, password.nullable()))
-            .first(&mut conn)
-            .map_err(|_| (StatusCode::UNAUTHORIZED, "User not found".to_string()))?;
+    let user: Option<(Uuid, Option<String>, bool)> = {
+        let req_email = req_email.clone();
+        db.run(move |conn| {
+            table::filter(table, email.eq(req_email))
+                .select((id, password.nullable(), totp_enabled))
+                .first(conn)
+                .optional()
+                .map_err(DbError::from)
+        })
+        .await?
+    };
 
-    // TODO: Deal with null password case.
-    let Some(password_hash) = password_hash else {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "This user has no password set! Contact administrator.".to_string(),
+    // Check the password even if there's no such user (or they have no password), and fail the
+    // same way in every case, so that neither the response nor its timing give away who has an
+    // account.
+    let (user_id, password_hash) = match &user {
+        Some((user_id, Some(password_hash), _)) => (Some(*user_id), password_hash.as_str()),
+        _ => (None, dummy_hash()),
+    };
+    let password_correct = verify_password(&req_password, password_hash)
+        .map_err(|_| AppError::Internal("Internal error occurred".to_string()))?;
+    let Some(user_id) = user_id.filter(|_| password_correct) else {
+        throttle
+            .record_failure(&req_email, client.ip.as_deref())
+            .await?;
+        return Err(AppError::Unauthorized(
+            "Invalid email or password".to_string(),
         ));
     };
 
-    let parsed_hash = PasswordHash::new(&password_hash).map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Internal error occurred".to_string(),
-        )
-    })?;
+    if needs_rehash(password_hash) {
+        db.run(move |conn| {
+            rehash_password(conn, user_id, &req_password);
+            DbResult::Ok(())
+        })
+        .await?;
+    }
 
-    if Argon2::default()
-        .verify_password(req_password.as_bytes(), &parsed_hash)
-        .is_err()
-    {
-        return Err((StatusCode::UNAUTHORIZED, "Incorrect password.".to_string()));
+    if user.is_some_and(|(_, _, mfa)| mfa) {
+        let challenge = db
+            .run(move |conn| mfa::create_challenge(conn, user_id).map_err(DbError::from))
+            .await?;
+        return Ok(Json(challenge).into_response());
     }
 
     // XXX: ONLY FROM THIS POINT ON ARE WE AUTHORIZED.
-    let mut sessions_wlock = sessions.write().await;
-    let session_id = Uuid::new_v4();
-    let expires = Utc::now()
-        .checked_add_days(Days::new(1))
-        .ok_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR, "Funky time".to_string()))?;
-    sessions_wlock.insert(session_id, Session { user_id, expires });
-
-    // TODO: I tried to do this with Set-Cookie header. But I am too stupid to work it out.
-    Ok(session_id.to_string())
+    throttle.reset(&req_email).await?;
+    let session = sessions.create(user_id, client).await?;
+
+    Ok(session_response(&sessions, jar, session, cookie))
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequestBody {
+    refresh_token: String,
+}
+
+/// Exchange a refresh token for a fresh session, so that clients don't have to ask for the password
+/// again when their session expires.
+///
+/// The refresh token is read from the request body, or failing that from the refresh cookie, in
+/// which case the new session is handed out in cookies too. Like other requests authenticated by
+/// cookies, the latter must carry the CSRF token.
+pub async fn refresh(
+    State(sessions): State<Sessions>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    jar: CookieJar,
+    body: Option<Json<RefreshRequestBody>>,
+) -> Result<Response, AppError> {
+    let (refresh_token, use_cookies) = match body {
+        Some(Json(RefreshRequestBody { refresh_token })) => (refresh_token, false),
+        None => {
+            let refresh_token = jar
+                .get(REFRESH_COOKIE)
+                .ok_or_else(|| AppError::Unauthorized("No refresh token".to_string()))?
+                .value()
+                .to_string();
+            check_csrf(&jar, &headers)?;
+            (refresh_token, true)
+        }
+    };
+
+    let session = sessions
+        .refresh(&refresh_token, client)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("Invalid or expired refresh token".to_string()))?;
+
+    Ok(session_response(&sessions, jar, session, use_cookies))
+}
+
+pub fn extract_session_id(headers: &HeaderMap) -> Result<Uuid, AppError> {
+    headers
+        .get("session_id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(|| AppError::Unauthorized("No session active".to_string()))
+}
+
+/// End the session making the request, clearing any session cookies.
+pub async fn logout(
+    State(sessions): State<Sessions>,
+    headers: HeaderMap,
+    jar: CookieJar,
+) -> Result<CookieJar, AppError> {
+    let session_id = extract_session_id(&headers)?;
+    sessions.delete(session_id).await?;
+    Ok([SESSION_COOKIE, REFRESH_COOKIE, CSRF_COOKIE]
+        .into_iter()
+        .fold(jar, |jar, name| jar.remove(Cookie::build(name).path("/"))))
+}
+
+#[derive(Serialize)]
+pub struct SessionView {
+    id: Uuid,
+    created_at: NaiveDateTime,
+    last_seen: NaiveDateTime,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    /// Whether this is the session making the request.
+    current: bool,
+}
+
+/// List the active sessions of the user making the request.
+pub async fn list_sessions(
+    State(sessions): State<Sessions>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SessionView>>, AppError> {
+    let user_id = extract_user_id(&headers)?;
+    let session_id = extract_session_id(&headers)?;
+    let sessions = sessions.list_for_user(user_id).await?;
+
+    Ok(Json(
+        sessions
+            .into_iter()
+            .map(|session| SessionView {
+                id: session.public_id,
+                created_at: session.created_at,
+                last_seen: session.last_seen,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                current: session.id == session_id,
+            })
+            .collect(),
+    ))
+}
+
+/// End one of the requesting user's sessions, e.g. one left open on another device.
+///
+/// Sessions are identified by the `id` given by [`list_sessions`], which is distinct from the
+/// secret session id used for authentication.
+pub async fn revoke_session(
+    State(sessions): State<Sessions>,
+    headers: HeaderMap,
+    Path(public_id): Path<Uuid>,
+) -> Result<(), AppError> {
+    let user_id = extract_user_id(&headers)?;
+    if !sessions.delete_for_user(user_id, public_id).await? {
+        return Err(AppError::NotFound("No such session".to_string()));
+    }
+    Ok(())
 }
@@ -0,0 +1,492 @@
+//! Browsing the problem bank, a page at a time.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use axum::{extract::State, http::HeaderMap};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Days, NaiveDate, NaiveDateTime};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    db::Db,
+    error::AppError,
+    extract::{Json, Query},
+    extract_user_id,
+    models::{Problem, Topic},
+};
+
+pub const DEFAULT_LIMIT: i64 = 20;
+pub const MAX_LIMIT: i64 = 100;
+
+/// Whether the user has attempted a problem, and how their last attempt went.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Unattempted,
+    Solved,
+    Failed,
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unattempted" => Ok(Self::Unattempted),
+            "solved" => Ok(Self::Solved),
+            "failed" => Ok(Self::Failed),
+            _ => Err(format!(
+                "unknown status `{s}`, expected `unattempted`, `solved` or `failed`"
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    #[default]
+    Newest,
+    Oldest,
+}
+
+impl Sort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Newest => "newest",
+            Self::Oldest => "oldest",
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(Self::Newest),
+            "oldest" => Ok(Self::Oldest),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Deserialize a comma-separated list, as query strings can't hold lists otherwise.
+pub fn comma_separated<'de, D, T>(d: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let Some(list) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    list.split(',')
+        .map(|item| item.trim().parse().map_err(de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filters {
+    /// Problems with a topic in this module.
+    module: Option<i32>,
+    /// Problems with any of these topics.
+    #[serde(default, deserialize_with = "comma_separated")]
+    topics: Option<Vec<i32>>,
+    /// Problems whose author contains this, ignoring case.
+    author: Option<String>,
+    /// Problems whose source contains this, ignoring case.
+    source: Option<String>,
+    /// Problems submitted by this user.
+    submitted_by: Option<Uuid>,
+    has_solution: Option<bool>,
+    has_image: Option<bool>,
+    /// Problems submitted on or after this day.
+    submitted_from: Option<NaiveDate>,
+    /// Problems submitted on or before this day.
+    submitted_to: Option<NaiveDate>,
+    /// Problems for which the user's status is any of these.
+    #[serde(default, deserialize_with = "comma_separated")]
+    status: Option<Vec<Status>>,
+    #[serde(default)]
+    sort: Sort,
+    limit: Option<i64>,
+    /// Where the previous page left off.
+    cursor: Option<String>,
+}
+
+impl Filters {
+    /// A short hash of the filters, which changes if any of them do.
+    fn fingerprint(&self) -> String {
+        // Taken apart so that new filters can't be forgotten here.
+        let Self {
+            module,
+            topics,
+            author,
+            source,
+            submitted_by,
+            has_solution,
+            has_image,
+            submitted_from,
+            submitted_to,
+            status,
+            sort: _,
+            limit: _,
+            cursor: _,
+        } = self;
+        let filters = format!(
+            "{:?}",
+            (
+                module,
+                topics,
+                author,
+                source,
+                submitted_by,
+                has_solution,
+                has_image,
+                submitted_from,
+                submitted_to,
+                status,
+            )
+        );
+        URL_SAFE_NO_PAD.encode(&Sha256::digest(filters)[..8])
+    }
+}
+
+/// Where a page ends: the submission time and id of its last problem. The order and filters of the
+/// listing are included too, as the position means nothing in any other.
+#[derive(PartialEq, Debug)]
+struct Cursor {
+    submitted_at: NaiveDateTime,
+    id: i32,
+    sort: Sort,
+    /// The [`Filters::fingerprint`] of the listing.
+    filters: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(format!(
+            "{}.{}.{}.{}",
+            self.submitted_at.and_utc().timestamp_micros(),
+            self.id,
+            self.sort.as_str(),
+            self.filters
+        ))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let cursor = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let mut parts = cursor.splitn(4, '.');
+        Some(Self {
+            submitted_at: NaiveDateTime::from_timestamp_micros(parts.next()?.parse().ok()?)?,
+            id: parts.next()?.parse().ok()?,
+            sort: parts.next()?.parse().ok()?,
+            filters: parts.next()?.to_string(),
+        })
+    }
+}
+
+/// Escape the wildcards in a string matched with `LIKE`.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// The topics of each of the problems.
+pub fn topics_of(
+    conn: &mut PgConnection,
+    problem_ids: &[i32],
+) -> QueryResult<HashMap<i32, Vec<Topic>>> {
+    use crate::schema::{problem_topic, topics};
+    let mut topics: HashMap<i32, Vec<Topic>> = HashMap::new();
+    for (problem_id, topic) in problem_topic::table
+        .inner_join(topics::table)
+        .filter(problem_topic::problem_id.eq_any(problem_ids))
+        .order(topics::id)
+        .select((problem_topic::problem_id, Topic::as_select()))
+        .load::<(i32, Topic)>(conn)?
+    {
+        topics.entry(problem_id).or_default().push(topic);
+    }
+    Ok(topics)
+}
+
+#[derive(Serialize)]
+pub struct Listing {
+    #[serde(flatten)]
+    problem: Problem,
+    topics: Vec<Topic>,
+    /// How many solutions the problem has.
+    solutions: i64,
+    status: Status,
+}
+
+#[derive(Serialize)]
+pub struct Page {
+    problems: Vec<Listing>,
+    /// Pass this as `cursor` to get the next page. Missing on the last page.
+    next_cursor: Option<String>,
+}
+
+/// List the problems matching the filters, a page at a time.
+pub async fn list_problems(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Query(filters): Query<Filters>,
+) -> Result<Json<Page>, AppError> {
+    use crate::schema::{problem_topic, problems, solutions, topics, user_problem};
+    let user_id = extract_user_id(&headers)?;
+    let limit = filters.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "The limit must be between 1 and {MAX_LIMIT}"
+        )));
+    }
+    let fingerprint = filters.fingerprint();
+    let cursor = filters
+        .cursor
+        .as_deref()
+        .map(|cursor| {
+            Cursor::decode(cursor).ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))
+        })
+        .transpose()?;
+    if cursor
+        .as_ref()
+        .is_some_and(|cursor| cursor.sort != filters.sort || cursor.filters != fingerprint)
+    {
+        return Err(AppError::BadRequest(
+            "The cursor is for a different sort or filters".to_string(),
+        ));
+    }
+
+    db.run(move |conn| {
+        // The user's last attempt at each problem, if they have made one.
+        let mut query = problems::table
+            .left_join(
+                user_problem::table.on(user_problem::problem_id
+                    .eq(problems::id)
+                    .and(user_problem::user_id.eq(user_id))),
+            )
+            .filter(problems::deleted_at.is_null())
+            .select((Problem::as_select(), user_problem::successful.nullable()))
+            .into_boxed();
+
+        if let Some(module_id) = filters.module {
+            query = query.filter(
+                problems::id.eq_any(
+                    problem_topic::table
+                        .inner_join(topics::table)
+                        .filter(topics::module_id.eq(module_id))
+                        .select(problem_topic::problem_id),
+                ),
+            );
+        }
+        if let Some(topic_ids) = filters.topics {
+            query = query.filter(
+                problems::id.eq_any(
+                    problem_topic::table
+                        .filter(problem_topic::topic_id.eq_any(topic_ids))
+                        .select(problem_topic::problem_id),
+                ),
+            );
+        }
+        if let Some(author) = filters.author {
+            query = query.filter(problems::author.ilike(format!("%{}%", escape_like(&author))));
+        }
+        if let Some(source) = filters.source {
+            query = query.filter(problems::source.ilike(format!("%{}%", escape_like(&source))));
+        }
+        if let Some(submitted_by) = filters.submitted_by {
+            query = query.filter(problems::user_id.eq(submitted_by));
+        }
+        if let Some(has_solution) = filters.has_solution {
+            let with_solutions = solutions::table.select(solutions::problem_id);
+            query = if has_solution {
+                query.filter(problems::id.eq_any(with_solutions))
+            } else {
+                query.filter(problems::id.ne_all(with_solutions))
+            };
+        }
+        match filters.has_image {
+            Some(true) => query = query.filter(problems::img_path.is_not_null()),
+            Some(false) => query = query.filter(problems::img_path.is_null()),
+            None => {}
+        }
+        if let Some(from) = filters.submitted_from {
+            query = query.filter(problems::submitted_at.ge(from.and_time(Default::default())));
+        }
+        if let Some(to) = filters
+            .submitted_to
+            .and_then(|to| to.checked_add_days(Days::new(1)))
+        {
+            query = query.filter(problems::submitted_at.lt(to.and_time(Default::default())));
+        }
+        if let Some(statuses) = filters.status {
+            let successful: Vec<bool> = statuses
+                .iter()
+                .filter_map(|status| match status {
+                    Status::Unattempted => None,
+                    Status::Solved => Some(true),
+                    Status::Failed => Some(false),
+                })
+                .collect();
+            let attempted = user_problem::successful.nullable().eq_any(successful);
+            query = if statuses.contains(&Status::Unattempted) {
+                query.filter(user_problem::successful.nullable().is_null().or(attempted))
+            } else {
+                query.filter(attempted)
+            };
+        }
+
+        if let Some(Cursor {
+            submitted_at, id, ..
+        }) = cursor
+        {
+            query = match filters.sort {
+                Sort::Newest => query.filter(
+                    problems::submitted_at
+                        .lt(submitted_at)
+                        .or(problems::submitted_at
+                            .eq(submitted_at)
+                            .and(problems::id.lt(id))),
+                ),
+                Sort::Oldest => query.filter(
+                    problems::submitted_at
+                        .gt(submitted_at)
+                        .or(problems::submitted_at
+                            .eq(submitted_at)
+                            .and(problems::id.gt(id))),
+                ),
+            };
+        }
+        query = match filters.sort {
+            Sort::Newest => query.order((problems::submitted_at.desc(), problems::id.desc())),
+            Sort::Oldest => query.order((problems::submitted_at.asc(), problems::id.asc())),
+        };
+
+        // One extra problem is loaded to tell whether there is another page.
+        let mut problems: Vec<(Problem, Option<bool>)> = query.limit(limit + 1).load(conn)?;
+        let next_cursor = if problems.len() as i64 > limit {
+            problems.truncate(limit as usize);
+            problems.last().map(|(problem, _)| {
+                Cursor {
+                    submitted_at: problem.submitted_at,
+                    id: problem.id,
+                    sort: filters.sort,
+                    filters: fingerprint,
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+
+        let ids: Vec<i32> = problems.iter().map(|(problem, _)| problem.id).collect();
+        let mut topics = topics_of(conn, &ids)?;
+        let solution_counts: HashMap<i32, i64> = solutions::table
+            .filter(solutions::problem_id.eq_any(&ids))
+            .group_by(solutions::problem_id)
+            .select((solutions::problem_id, diesel::dsl::count(solutions::id)))
+            .load(conn)?
+            .into_iter()
+            .collect();
+
+        let problems = problems
+            .into_iter()
+            .map(|(problem, successful)| Listing {
+                topics: topics.remove(&problem.id).unwrap_or_default(),
+                solutions: solution_counts.get(&problem.id).copied().unwrap_or(0),
+                status: match successful {
+                    None => Status::Unattempted,
+                    Some(true) => Status::Solved,
+                    Some(false) => Status::Failed,
+                },
+                problem,
+            })
+            .collect();
+        Ok(Page {
+            problems,
+            next_cursor,
+        })
+    })
+    .await
+    .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cursor() -> Cursor {
+        Cursor {
+            submitted_at: NaiveDate::from_ymd_opt(2026, 10, 18)
+                .unwrap()
+                .and_hms_micro_opt(12, 34, 56, 789_012)
+                .unwrap(),
+            id: 42,
+            sort: Sort::Oldest,
+            filters: "AAAAAAAAAAA".to_string(),
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = cursor();
+        assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        let encoded = |s: &str| URL_SAFE_NO_PAD.encode(s);
+        for cursor in [
+            String::new(),
+            "not base64!".to_string(),
+            URL_SAFE_NO_PAD.encode([0xff, 0xfe]),
+            encoded("1760790896789012.42"),
+            encoded("1760790896789012.42.sideways.AAAAAAAAAAA"),
+            encoded("yesterday.42.newest.AAAAAAAAAAA"),
+            encoded("1760790896789012.forty-two.newest.AAAAAAAAAAA"),
+        ] {
+            assert_eq!(Cursor::decode(&cursor), None, "{cursor}");
+        }
+    }
+
+    fn filters(query: serde_json::Value) -> Filters {
+        serde_json::from_value(query).unwrap()
+    }
+
+    #[test]
+    fn fingerprints_change_with_the_filters_only() {
+        let fingerprint = filters(json!({ "module": 1, "topics": "2,3" })).fingerprint();
+        assert_eq!(
+            filters(json!({ "module": 1, "topics": "2,3", "limit": 5, "cursor": "abc" }))
+                .fingerprint(),
+            fingerprint
+        );
+        assert_ne!(
+            filters(json!({ "module": 1, "topics": "2,4" })).fingerprint(),
+            fingerprint
+        );
+        assert_ne!(
+            filters(json!({ "module": 1, "topics": "2,3", "has_image": true })).fingerprint(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("plain"), "plain");
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("a_b"), "a\\_b");
+        assert_eq!(escape_like("C:\\%_"), "C:\\\\\\%\\_");
+    }
+}
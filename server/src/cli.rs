@@ -0,0 +1,200 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::{Days, Utc};
+use clap::{Parser, Subcommand};
+use diesel::prelude::*;
+use diesel_migrations::MigrationHarness;
+use uuid::Uuid;
+
+use crate::{
+    admin::{self, NewAccessToken},
+    auth::hash_password,
+    email::normalise_email,
+    establish_connection, insert_problem,
+    models::{NewProblem, Role},
+    MIGRATIONS,
+};
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file to read. Defaults to `watson.toml`, if it exists.
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// What to do. Defaults to running the server.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server, after applying any pending migrations.
+    Serve,
+    /// Apply any pending database migrations.
+    Migrate,
+    /// Manage registration access tokens.
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+    /// Manage users.
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Import problems from a JSON file.
+    ///
+    /// The file should contain an array of problems, each in the same format accepted by the
+    /// `/problems/create` endpoint. Either every problem is imported, or none are.
+    Import {
+        file: PathBuf,
+        /// Email address of the user to attribute the problems to.
+        #[arg(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Issue a new access token, and print it.
+    Create {
+        /// Display name of the user registering with the token.
+        #[arg(long)]
+        name: String,
+        /// How many users can register with the token.
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(i32).range(1..))]
+        max_redemptions: i32,
+        /// Number of days until the token expires. By default it never expires.
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+        /// Cohort to put users registering with the token in.
+        #[arg(long)]
+        cohort: Option<String>,
+        /// Id of a module to enrol users registering with the token on.
+        #[arg(long)]
+        module: Option<i32>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Set a new password for a user, read from standard input, and end all of their sessions.
+    ResetPassword { email: String },
+    /// Change the role of a user.
+    Promote {
+        email: String,
+        #[arg(long, default_value = "admin")]
+        role: Role,
+    },
+}
+
+/// Run an administrative command.
+pub fn run(command: Command) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut conn = establish_connection();
+    match command {
+        Command::Serve => unreachable!("the server is started from `main`"),
+        Command::Migrate => {
+            let applied = conn.run_pending_migrations(MIGRATIONS)?;
+            println!("Applied {} migration(s)", applied.len());
+        }
+        Command::Token {
+            command:
+                TokenCommand::Create {
+                    name,
+                    max_redemptions,
+                    expires_in_days,
+                    cohort,
+                    module,
+                },
+        } => {
+            let expires = expires_in_days
+                .map(|days| {
+                    Utc::now()
+                        .naive_utc()
+                        .checked_add_days(Days::new(days))
+                        .ok_or("Expiry date out of range")
+                })
+                .transpose()?;
+            let token = admin::insert_token(
+                &mut conn,
+                None,
+                NewAccessToken {
+                    name,
+                    max_redemptions,
+                    expires,
+                    cohort,
+                    module_id: module,
+                },
+            )?;
+            println!("{}", token.id);
+        }
+        Command::User {
+            command: UserCommand::ResetPassword { email },
+        } => {
+            use crate::schema::{sessions, users};
+            let user_id = find_user(&mut conn, &email)?;
+
+            eprint!("New password: ");
+            io::stderr().flush()?;
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                return Err("Password must not be empty".into());
+            }
+            let hashed_password = hash_password(password).map_err(|e| e.to_string())?;
+
+            conn.transaction(|conn| {
+                diesel::update(users::table.find(user_id))
+                    .set(users::password.eq(hashed_password))
+                    .execute(conn)?;
+                diesel::delete(sessions::table.filter(sessions::user_id.eq(user_id))).execute(conn)
+            })?;
+            eprintln!("Password reset for {email}");
+        }
+        Command::User {
+            command: UserCommand::Promote { email, role },
+        } => {
+            use crate::schema::users;
+            let user_id = find_user(&mut conn, &email)?;
+            diesel::update(users::table.find(user_id))
+                .set(users::role.eq(role))
+                .execute(&mut conn)?;
+            eprintln!("{email} is now a {role}");
+        }
+        Command::Import { file, user } => {
+            let problems: Vec<NewProblem> = serde_json::from_str(&fs::read_to_string(&file)?)?;
+            let user_id = user.map(|email| find_user(&mut conn, &email)).transpose()?;
+            if let Some(i) = problems
+                .iter()
+                .position(|p| p.problem.body.is_none() && p.problem.img_path.is_none())
+            {
+                return Err(format!("Problem {i} has neither a body nor an image").into());
+            }
+
+            let n_problems = problems.len();
+            conn.transaction(|conn| {
+                problems
+                    .into_iter()
+                    .try_for_each(|problem| insert_problem(conn, user_id, problem).map(|_| ()))
+            })?;
+            eprintln!("Imported {n_problems} problem(s)");
+        }
+    }
+    Ok(())
+}
+
+fn find_user(conn: &mut PgConnection, email: &str) -> Result<Uuid, Box<dyn Error + Send + Sync>> {
+    use crate::schema::users;
+    users::table
+        .filter(users::email.eq(normalise_email(email)))
+        .select(users::id)
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| format!("No user with email {email}").into())
+}
@@ -0,0 +1,508 @@
+//! Configuration of the server, read from a TOML file and then overridden by environment variables.
+//!
+//! Every setting has a default (except for the database URL), so the file is optional. See
+//! `watson.example.toml` for the settings available, and the environment variable overriding each.
+
+use std::{
+    env,
+    fmt::{self, Display},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+
+use axum::http::HeaderValue;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    auth::Argon2Config,
+    db::DbConfig,
+    logging::LogConfig,
+    mail::{self, MailConfig, MailTransport},
+    metrics::MetricsConfig,
+    oidc::OidcConfig,
+    session::SessionConfig,
+    throttle::ThrottleConfig,
+};
+
+/// The file read if no other is given, if it exists.
+const DEFAULT_PATH: &str = "watson.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub database: DbConfig,
+    pub session: SessionConfig,
+    pub login_throttle: ThrottleConfig,
+    pub revision: RevisionConfig,
+    pub argon2: Argon2Config,
+    pub mail: MailConfig,
+    pub metrics: MetricsConfig,
+    /// Single sign-on, which is disabled unless configured.
+    pub oidc: Option<OidcConfig>,
+}
+
+/// How the HTTP server is set up.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on.
+    pub bind: SocketAddr,
+    /// Origins from which the client may make requests.
+    pub cors_origins: Vec<String>,
+    /// Directory which uploaded images are stored in.
+    pub media_path: PathBuf,
+    /// Base URL of the client, which links in emails point at.
+    pub app_url: String,
+    /// How long to wait for requests in progress to finish when shutting down.
+    #[serde(rename = "shutdown_timeout_seconds", deserialize_with = "seconds")]
+    pub shutdown_timeout: std::time::Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let client = if cfg!(debug_assertions) {
+            "http://localhost:5173"
+        } else {
+            "https://watson-project.com"
+        };
+        let mut cors_origins = vec![client.to_string()];
+        if cfg!(debug_assertions) {
+            cors_origins.push("http://localhost:4173".to_string());
+        }
+
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            cors_origins,
+            media_path: PathBuf::from("media"),
+            app_url: client.to_string(),
+            shutdown_timeout: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+/// How long to wait before serving a user a problem they have already attempted.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RevisionConfig {
+    #[serde(rename = "after_success_weeks", deserialize_with = "weeks")]
+    pub after_success: chrono::Duration,
+    #[serde(rename = "after_failure_weeks", deserialize_with = "weeks")]
+    pub after_failure: chrono::Duration,
+}
+
+impl Default for RevisionConfig {
+    fn default() -> Self {
+        Self {
+            after_success: chrono::Duration::weeks(4),
+            after_failure: chrono::Duration::weeks(1),
+        }
+    }
+}
+
+/// The configuration was missing, or had something wrong with it.
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid configuration:")?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the configuration from `path`, or `watson.toml` if there is no `path` and it exists,
+    /// then apply overrides from the environment and check that the result makes sense.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => Self::read(path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::read(Path::new(DEFAULT_PATH))?,
+            None => Self::default(),
+        };
+
+        let mut errors = Vec::new();
+        config.override_from_env(&mut EnvOverrides(&mut errors));
+        config.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError(vec![format!("Couldn't read {}: {e}", path.display())]))?;
+        toml::from_str(&contents)
+            .map_err(|e| ConfigError(vec![format!("Couldn't parse {}: {e}", path.display())]))
+    }
+
+    fn override_from_env(&mut self, overrides: &mut EnvOverrides) {
+        let server = &mut self.server;
+        overrides.set("BIND_ADDRESS", &mut server.bind);
+        overrides.set_with(
+            "CORS_ORIGINS",
+            &mut server.cors_origins,
+            |origins: String| {
+                origins
+                    .split(',')
+                    .map(|origin| origin.trim().to_string())
+                    .collect()
+            },
+        );
+        overrides.set("MEDIA_PATH", &mut server.media_path);
+        overrides.set("APP_URL", &mut server.app_url);
+        overrides.set_with(
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            &mut server.shutdown_timeout,
+            std::time::Duration::from_secs,
+        );
+
+        overrides.set("LOG_FORMAT", &mut self.log.format);
+        overrides.set("RUST_LOG", &mut self.log.filter);
+
+        overrides.set_with("METRICS_BIND", &mut self.metrics.bind, Some);
+        overrides.set_with("METRICS_TOKEN", &mut self.metrics.token, Some);
+
+        let database = &mut self.database;
+        overrides.set("DATABASE_URL", &mut database.url);
+        overrides.set("DB_POOL_SIZE", &mut database.pool_size);
+        overrides.set_with(
+            "DB_CONNECTION_TIMEOUT_SECONDS",
+            &mut database.connection_timeout,
+            std::time::Duration::from_secs,
+        );
+
+        let session = &mut self.session;
+        overrides.set_with(
+            "SESSION_IDLE_MINUTES",
+            &mut session.idle_timeout,
+            chrono::Duration::minutes,
+        );
+        overrides.set_with(
+            "SESSION_ABSOLUTE_MINUTES",
+            &mut session.absolute_timeout,
+            chrono::Duration::minutes,
+        );
+        overrides.set_with(
+            "REFRESH_TOKEN_DAYS",
+            &mut session.refresh_timeout,
+            chrono::Duration::days,
+        );
+
+        let throttle = &mut self.login_throttle;
+        overrides.set(
+            "LOGIN_FAILURES_BEFORE_LOCKOUT",
+            &mut throttle.email_failures,
+        );
+        overrides.set(
+            "LOGIN_IP_FAILURES_BEFORE_LOCKOUT",
+            &mut throttle.ip_failures,
+        );
+        overrides.set_with(
+            "LOGIN_MAX_LOCKOUT_MINUTES",
+            &mut throttle.max_lockout,
+            chrono::Duration::minutes,
+        );
+
+        let revision = &mut self.revision;
+        overrides.set_with(
+            "REVISION_AFTER_SUCCESS_WEEKS",
+            &mut revision.after_success,
+            chrono::Duration::weeks,
+        );
+        overrides.set_with(
+            "REVISION_AFTER_FAILURE_WEEKS",
+            &mut revision.after_failure,
+            chrono::Duration::weeks,
+        );
+
+        let argon2 = &mut self.argon2;
+        overrides.set("ARGON2_MEMORY_KIB", &mut argon2.memory_kib);
+        overrides.set("ARGON2_ITERATIONS", &mut argon2.iterations);
+        overrides.set("ARGON2_PARALLELISM", &mut argon2.parallelism);
+
+        let mail = &mut self.mail;
+        overrides.set("MAIL_TRANSPORT", &mut mail.transport);
+        overrides.set_with("SMTP_URL", &mut mail.smtp_url, Some);
+        overrides.set_with("MAIL_FROM", &mut mail.from, Some);
+        overrides.set_with("MAIL_DIR", &mut mail.dir, Some);
+
+        if let Ok(issuer) = env::var("OIDC_ISSUER") {
+            self.oidc.get_or_insert_with(OidcConfig::default).issuer = issuer;
+        }
+        if let Some(oidc) = &mut self.oidc {
+            overrides.set("OIDC_CLIENT_ID", &mut oidc.client_id);
+            overrides.set_with("OIDC_CLIENT_SECRET", &mut oidc.client_secret, Some);
+            overrides.set("OIDC_REDIRECT_URI", &mut oidc.redirect_uri);
+            overrides.set("OIDC_SCOPES", &mut oidc.scopes);
+            overrides.set("OIDC_ALLOW_HMAC", &mut oidc.allow_hmac);
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        fn check(errors: &mut Vec<String>, ok: bool, error: &str) {
+            if !ok {
+                errors.push(error.to_string());
+            }
+        }
+
+        let zero = chrono::Duration::zero();
+
+        for origin in &self.server.cors_origins {
+            check(
+                errors,
+                origin.parse::<HeaderValue>().is_ok(),
+                &format!("server.cors_origins: invalid origin {origin:?}"),
+            );
+        }
+        check(
+            errors,
+            !self.server.app_url.is_empty(),
+            "server.app_url (APP_URL) must not be empty",
+        );
+
+        if let Err(e) = self.log.env_filter() {
+            errors.push(format!("log.filter (RUST_LOG) is invalid: {e}"));
+        }
+
+        check(
+            errors,
+            self.metrics
+                .token
+                .as_ref()
+                .map_or(true, |token| !token.is_empty()),
+            "metrics.token (METRICS_TOKEN) must not be empty",
+        );
+
+        check(
+            errors,
+            !self.database.url.is_empty(),
+            "database.url (DATABASE_URL) must be set",
+        );
+        check(
+            errors,
+            self.database.pool_size > 0,
+            "database.pool_size (DB_POOL_SIZE) must be positive",
+        );
+
+        let session = &self.session;
+        check(
+            errors,
+            session.idle_timeout > zero,
+            "session.idle_minutes (SESSION_IDLE_MINUTES) must be positive",
+        );
+        check(
+            errors,
+            session.absolute_timeout >= session.idle_timeout,
+            "session.absolute_minutes (SESSION_ABSOLUTE_MINUTES) must be at least \
+             session.idle_minutes",
+        );
+        check(
+            errors,
+            session.refresh_timeout > zero,
+            "session.refresh_token_days (REFRESH_TOKEN_DAYS) must be positive",
+        );
+
+        let throttle = &self.login_throttle;
+        check(
+            errors,
+            throttle.email_failures > 0,
+            "login_throttle.failures_before_lockout (LOGIN_FAILURES_BEFORE_LOCKOUT) must be \
+             positive",
+        );
+        check(
+            errors,
+            throttle.ip_failures > 0,
+            "login_throttle.ip_failures_before_lockout (LOGIN_IP_FAILURES_BEFORE_LOCKOUT) must \
+             be positive",
+        );
+        check(
+            errors,
+            throttle.max_lockout > zero,
+            "login_throttle.max_lockout_minutes (LOGIN_MAX_LOCKOUT_MINUTES) must be positive",
+        );
+
+        check(
+            errors,
+            self.revision.after_success >= zero && self.revision.after_failure >= zero,
+            "revision intervals must not be negative",
+        );
+
+        if let Err(e) = self.argon2.params() {
+            errors.push(format!("argon2: invalid parameters: {e}"));
+        }
+
+        let mail = &self.mail;
+        match mail.transport {
+            MailTransport::Smtp => {
+                match &mail.smtp_url {
+                    Some(url) => {
+                        if let Err(e) = mail::check_smtp_url(url) {
+                            errors.push(format!("mail.smtp_url (SMTP_URL) is invalid: {e}"));
+                        }
+                    }
+                    None => errors.push(
+                        "mail.smtp_url (SMTP_URL) must be set to send mail by SMTP".to_string(),
+                    ),
+                }
+                match &mail.from {
+                    Some(from) => {
+                        if let Err(e) = from.parse::<lettre::message::Mailbox>() {
+                            errors.push(format!("mail.from (MAIL_FROM) is invalid: {e}"));
+                        }
+                    }
+                    None => errors
+                        .push("mail.from (MAIL_FROM) must be set to send mail by SMTP".to_string()),
+                }
+            }
+            MailTransport::File if mail.dir.is_none() => {
+                errors.push("mail.dir (MAIL_DIR) must be set to write mail to files".to_string())
+            }
+            MailTransport::File | MailTransport::Stdout => {}
+        }
+
+        if let Some(oidc) = &self.oidc {
+            check(
+                errors,
+                !oidc.issuer.is_empty(),
+                "oidc.issuer (OIDC_ISSUER) must not be empty",
+            );
+            check(
+                errors,
+                !oidc.client_id.is_empty(),
+                "oidc.client_id (OIDC_CLIENT_ID) must be set to use single sign-on",
+            );
+            check(
+                errors,
+                !oidc.redirect_uri.is_empty(),
+                "oidc.redirect_uri (OIDC_REDIRECT_URI) must be set to use single sign-on",
+            );
+        }
+    }
+}
+
+/// Set the configuration used by the rest of the program. Call this once, on startup.
+pub fn init(config: Config) {
+    CONFIG
+        .set(config)
+        .unwrap_or_else(|_| panic!("configuration already loaded"));
+}
+
+/// The configuration loaded on startup.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("configuration not loaded")
+}
+
+/// Use the default configuration, for tests of code which reads it.
+#[cfg(test)]
+pub fn init_default() {
+    CONFIG.get_or_init(Config::default);
+}
+
+/// Applies environment variables over the configuration, collecting any which can't be parsed.
+struct EnvOverrides<'a>(&'a mut Vec<String>);
+
+impl EnvOverrides<'_> {
+    /// Replace `field` with the value of the environment variable `key`, if it is set.
+    fn set<T>(&mut self, key: &str, field: &mut T)
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.set_with(key, field, |val: T| val);
+    }
+
+    /// Replace `field` with `f` applied to the value of the environment variable `key`, if it is
+    /// set.
+    fn set_with<T, U>(&mut self, key: &str, field: &mut U, f: impl FnOnce(T) -> U)
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let Ok(val) = env::var(key) else {
+            return;
+        };
+        match val.parse() {
+            Ok(val) => *field = f(val),
+            Err(e) => self.0.push(format!("{key}: invalid value {val:?}: {e}")),
+        }
+    }
+}
+
+/// Deserialize a whole number of seconds.
+pub fn seconds<'de, D: Deserializer<'de>>(d: D) -> Result<std::time::Duration, D::Error> {
+    u64::deserialize(d).map(std::time::Duration::from_secs)
+}
+
+/// Deserialize a whole number of minutes.
+pub fn minutes<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::Duration, D::Error> {
+    i64::deserialize(d).map(chrono::Duration::minutes)
+}
+
+/// Deserialize a whole number of days.
+pub fn days<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::Duration, D::Error> {
+    i64::deserialize(d).map(chrono::Duration::days)
+}
+
+/// Deserialize a whole number of weeks.
+pub fn weeks<'de, D: Deserializer<'de>>(d: D) -> Result<chrono::Duration, D::Error> {
+    i64::deserialize(d).map(chrono::Duration::weeks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_is_valid() {
+        let config: Config = toml::from_str(include_str!("../watson.example.toml")).unwrap();
+        let mut errors = Vec::new();
+        config.validate(&mut errors);
+        assert_eq!(errors, Vec::<String>::new());
+        assert_eq!(config.session.idle_timeout, chrono::Duration::minutes(120));
+        assert_eq!(config.revision.after_success, chrono::Duration::weeks(4));
+    }
+
+    #[test]
+    fn invalid_settings_are_all_reported() {
+        let config: Config = toml::from_str(
+            r#"
+            [database]
+            pool_size = 0
+
+            [mail]
+            transport = "smtp"
+            "#,
+        )
+        .unwrap();
+        let mut errors = Vec::new();
+        config.validate(&mut errors);
+        assert_eq!(
+            errors,
+            [
+                "database.url (DATABASE_URL) must be set",
+                "database.pool_size (DB_POOL_SIZE) must be positive",
+                "mail.smtp_url (SMTP_URL) must be set to send mail by SMTP",
+                "mail.from (MAIL_FROM) must be set to send mail by SMTP",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Config>("[session]\nidle_hours = 2\n").is_err());
+    }
+}
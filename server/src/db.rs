@@ -0,0 +1,126 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use diesel::{
+    pg::PgConnection,
+    r2d2::{ConnectionManager, Pool, PoolError, State},
+    result::Error as QueryError,
+};
+use serde::Deserialize;
+
+/// Database work taking longer than this is logged as a warning.
+const SLOW_QUERY: Duration = Duration::from_millis(500);
+
+/// How the database connection pool is set up.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub url: String,
+    /// The most connections which are kept open at once.
+    pub pool_size: u32,
+    /// How long to wait for a connection before giving up on a request.
+    #[serde(
+        rename = "connection_timeout_seconds",
+        deserialize_with = "crate::config::seconds"
+    )]
+    pub connection_timeout: Duration,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            pool_size: 10,
+            connection_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// An error from running something against the database.
+#[derive(Debug)]
+pub enum DbError {
+    /// No connection could be had, e.g. because the database is down or every connection is busy.
+    Unavailable(PoolError),
+    Query(QueryError),
+}
+
+pub type DbResult<T> = Result<T, DbError>;
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(e) => write!(f, "Database unavailable: {e}"),
+            Self::Query(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<QueryError> for DbError {
+    fn from(e: QueryError) -> Self {
+        Self::Query(e)
+    }
+}
+
+/// A pool of database connections.
+#[derive(Clone)]
+pub struct Db {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl Db {
+    /// Create the pool. No connections are opened until they are needed, so this succeeds even if
+    /// the database is down.
+    pub fn new(config: DbConfig) -> Self {
+        let pool = Pool::builder()
+            .max_size(config.pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(config.connection_timeout)
+            .build_unchecked(ConnectionManager::new(config.url));
+        Self { pool }
+    }
+
+    /// How many connections are open, and how many of those are idle.
+    pub fn state(&self) -> State {
+        self.pool.state()
+    }
+
+    /// Run `f` with a connection from the pool. Diesel blocks, so this happens on a thread set
+    /// aside for blocking work rather than holding up the async executor.
+    ///
+    /// How long was spent waiting for a connection and running `f` is logged, in the span of the
+    /// request being handled.
+    ///
+    /// `f` may return any error which a [`DbError`] converts into, so that handlers can return
+    /// their own errors from it.
+    pub async fn run<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<DbError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let start = Instant::now();
+            let mut conn = pool.get().map_err(DbError::Unavailable)?;
+            let acquired = Instant::now();
+            let result = f(&mut conn);
+            let query = acquired.elapsed();
+            let wait_ms = (acquired - start).as_millis() as u64;
+            let query_ms = query.as_millis() as u64;
+            if query >= SLOW_QUERY {
+                tracing::warn!(wait_ms, query_ms, "Slow database query");
+            } else {
+                tracing::debug!(wait_ms, query_ms, "Database query");
+            }
+            result
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
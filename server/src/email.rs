@@ -0,0 +1,242 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::HeaderMap};
+use chrono::{Duration, Utc};
+use diesel::{
+    prelude::*,
+    result::{DatabaseErrorKind, Error},
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    auth::confirm_password,
+    config,
+    db::Db,
+    error::{internal_error, AppError},
+    extract::Json,
+    extract_user_id,
+    mail::{self, Email, Mailer},
+    session::ClientInfo,
+    throttle::LoginThrottle,
+    token,
+};
+
+/// How long an email verification link can be used for.
+const VERIFICATION_TOKEN_HOURS: i64 = 24;
+
+/// Put an email address in the form it is stored in, so that addresses differing only in case or
+/// surrounding whitespace are treated as the same.
+pub fn normalise_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Normalise an email address given by a user, rejecting it if it is obviously not an address.
+pub fn validate_email(email: &str) -> Result<String, AppError> {
+    let email = normalise_email(email);
+    let valid = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+                && email.len() <= 254
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(AppError::BadRequest("Invalid email address".to_string()));
+    }
+    Ok(email)
+}
+
+/// Map an error from writing an email address, reporting when it belongs to someone else.
+pub fn email_in_use(e: Error) -> AppError {
+    match e {
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            AppError::Conflict("Email address already in use".to_string())
+        }
+        e => internal_error(e),
+    }
+}
+
+/// Email a link which verifies that `email` belongs to the user, and switches the user to it if it
+/// isn't already their address. Any previous link sent to the user stops working.
+pub fn send_verification(
+    conn: &mut PgConnection,
+    mailer: Arc<dyn Mailer>,
+    user_id: Uuid,
+    email: String,
+) -> QueryResult<()> {
+    use crate::schema::email_verification_tokens;
+    let now = Utc::now().naive_utc();
+    let verification_token = token::generate();
+    conn.transaction(|conn| {
+        diesel::delete(
+            email_verification_tokens::table.filter(
+                email_verification_tokens::user_id
+                    .eq(user_id)
+                    .or(email_verification_tokens::expires.lt(now)),
+            ),
+        )
+        .execute(conn)?;
+        diesel::insert_into(email_verification_tokens::table)
+            .values((
+                email_verification_tokens::token_hash.eq(token::hash(&verification_token)),
+                email_verification_tokens::user_id.eq(user_id),
+                email_verification_tokens::email.eq(&email),
+                email_verification_tokens::expires
+                    .eq(now + Duration::hours(VERIFICATION_TOKEN_HOURS)),
+            ))
+            .execute(conn)
+    })?;
+
+    let email = Email {
+        to: email,
+        subject: "Verify your Watson email address".to_string(),
+        body: format!(
+            "To confirm that this is your email address, visit\n\n\
+             {}/verify-email?token={verification_token}\n\nThis link expires in \
+             {VERIFICATION_TOKEN_HOURS} hours. If you don't have a Watson account, you can ignore \
+             this email.",
+            config::get().server.app_url
+        ),
+    };
+    mail::spawn_send(mailer, email);
+    Ok(())
+}
+
+/// Send the user another link to verify their current email address.
+pub async fn resend_verification(
+    State(db): State<Db>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+    let user_id = extract_user_id(&headers)?;
+    db.run(move |conn| {
+        let (email, verified): (String, bool) = users::table
+            .find(user_id)
+            .select((users::email, users::email_verified))
+            .first(conn)?;
+        if verified {
+            return Err(AppError::Conflict(
+                "Email address already verified".to_string(),
+            ));
+        }
+
+        send_verification(conn, mailer, user_id, email).map_err(internal_error)
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailBody {
+    token: String,
+}
+
+/// Confirm an email address using the token from a verification link.
+pub async fn verify_email(
+    State(db): State<Db>,
+    Json(VerifyEmailBody { token }): Json<VerifyEmailBody>,
+) -> Result<(), AppError> {
+    use crate::schema::{email_verification_tokens, users};
+    db.run(move |conn| {
+        let verified = conn
+            .transaction(|conn| {
+                // Deleting the token as we look it up means it can only ever be used once.
+                let Some((user_id, email)) = diesel::delete(
+                    email_verification_tokens::table
+                        .find(token::hash(&token))
+                        .filter(email_verification_tokens::expires.gt(Utc::now().naive_utc())),
+                )
+                .returning((
+                    email_verification_tokens::user_id,
+                    email_verification_tokens::email,
+                ))
+                .get_result::<(Uuid, String)>(conn)
+                .optional()?
+                else {
+                    return Ok(false);
+                };
+
+                diesel::update(users::table.find(user_id))
+                    .set((users::email.eq(email), users::email_verified.eq(true)))
+                    .execute(conn)?;
+                diesel::delete(
+                    email_verification_tokens::table
+                        .filter(email_verification_tokens::user_id.eq(user_id)),
+                )
+                .execute(conn)?;
+                QueryResult::Ok(true)
+            })
+            .map_err(email_in_use)?;
+
+        if !verified {
+            return Err(AppError::BadRequest(
+                "Invalid or expired verification token".to_string(),
+            ));
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailBody {
+    email: String,
+    password: String,
+}
+
+/// Start changing the user's email address. The change only takes effect once the new address
+/// has been verified, and the old address is told about it.
+pub async fn change_email(
+    State(db): State<Db>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(ChangeEmailBody { email, password }): Json<ChangeEmailBody>,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+    let user_id = extract_user_id(&headers)?;
+    let new_email = validate_email(&email)?;
+    confirm_password(&db, &throttle, &client, user_id, password).await?;
+    db.run(move |conn| {
+        let old_email: String = users::table
+            .find(user_id)
+            .select(users::email)
+            .first(conn)?;
+
+        if new_email == old_email {
+            return Err(AppError::BadRequest(
+                "That is already your email address".to_string(),
+            ));
+        }
+        let taken: bool = diesel::select(diesel::dsl::exists(
+            users::table.filter(users::email.eq(&new_email)),
+        ))
+        .get_result(conn)?;
+        if taken {
+            return Err(AppError::Conflict(
+                "Email address already in use".to_string(),
+            ));
+        }
+
+        send_verification(conn, mailer.clone(), user_id, new_email.clone())?;
+        mail::spawn_send(
+            mailer,
+            Email {
+                to: old_email,
+                subject: "Your Watson email address is changing".to_string(),
+                body: format!(
+                    "Someone asked to change the email address of your Watson account to \
+                     {new_email}. The change will take effect once the new address is verified. If \
+                     this wasn't you, reset your password straight away."
+                ),
+            },
+        );
+        Ok(())
+    })
+    .await
+}
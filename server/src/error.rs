@@ -0,0 +1,214 @@
+use std::fmt;
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Duration;
+use diesel::result::{DatabaseErrorKind, Error as QueryError};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{db::DbError, logging};
+
+/// An error from handling a request.
+///
+/// It is sent to the client as JSON of the form
+/// `{"code": ..., "message": ..., "details": ..., "request_id": ...}`, where `code` is one of a
+/// fixed set of strings which clients can match on, `message` is for showing to the user,
+/// `details` is `null` or an object with more information, and `request_id` identifies the request
+/// in the logs.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// The request was understood, but its contents aren't acceptable.
+    Unprocessable(String),
+    /// A write broke a constraint in the database. The name of the constraint is given in the
+    /// details, so that clients can tell what was wrong.
+    Constraint {
+        kind: DatabaseErrorKind,
+        constraint: Option<String>,
+    },
+    /// Too many failed login attempts have been made, so no more are allowed for this long.
+    TooManyAttempts(Duration),
+    /// A server we rely on, such as the identity provider, failed.
+    BadGateway(String),
+    /// The database can't be reached.
+    Unavailable,
+    /// Something went wrong on our side. The cause is logged, but not shown to the client.
+    Internal(String),
+}
+
+/// Turn any error into an [`AppError::Internal`].
+pub fn internal_error<E: fmt::Display>(error: E) -> AppError {
+    AppError::Internal(error.to_string())
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+    request_id: Option<String>,
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Constraint {
+                kind: DatabaseErrorKind::UniqueViolation,
+                ..
+            } => StatusCode::CONFLICT,
+            Self::Constraint { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::Conflict(_) => "conflict",
+            Self::Unprocessable(_) => "unprocessable",
+            Self::Constraint {
+                kind: DatabaseErrorKind::UniqueViolation,
+                ..
+            } => "already_exists",
+            Self::Constraint {
+                kind: DatabaseErrorKind::ForeignKeyViolation,
+                ..
+            } => "unknown_reference",
+            Self::Constraint { .. } => "invalid_value",
+            Self::TooManyAttempts(_) => "too_many_attempts",
+            Self::BadGateway(_) => "bad_gateway",
+            Self::Unavailable => "unavailable",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Self::BadRequest(message)
+            | Self::Unauthorized(message)
+            | Self::Forbidden(message)
+            | Self::NotFound(message)
+            | Self::Conflict(message)
+            | Self::Unprocessable(message)
+            | Self::BadGateway(message) => message.clone(),
+            Self::Constraint {
+                kind: DatabaseErrorKind::UniqueViolation,
+                ..
+            } => "That already exists".to_string(),
+            Self::Constraint {
+                kind: DatabaseErrorKind::ForeignKeyViolation,
+                ..
+            } => "That refers to something which doesn't exist".to_string(),
+            Self::Constraint { .. } => "That value isn't allowed".to_string(),
+            Self::TooManyAttempts(wait) => format!(
+                "Too many failed login attempts. Try again in {} seconds.",
+                retry_after(*wait)
+            ),
+            Self::Unavailable => "Database unavailable".to_string(),
+            Self::Internal(_) => "Internal error occurred".to_string(),
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            Self::Constraint {
+                constraint: Some(constraint),
+                ..
+            } => Some(json!({ "constraint": constraint })),
+            Self::TooManyAttempts(wait) => Some(json!({ "retry_after": retry_after(*wait) })),
+            _ => None,
+        }
+    }
+}
+
+/// Whole seconds to wait, rounded up so that clients don't retry too early.
+fn retry_after(wait: Duration) -> i64 {
+    wait.num_seconds() + 1
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Internal(cause) => write!(f, "Internal error: {cause}"),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match &self {
+            Self::Internal(cause) => tracing::error!("Internal error: {cause}"),
+            Self::BadGateway(message) => tracing::warn!("Bad gateway: {message}"),
+            _ => tracing::debug!("Request failed: {self}"),
+        }
+        let body = Json(ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            details: self.details(),
+            request_id: logging::current_request_id(),
+        });
+        match self {
+            Self::TooManyAttempts(wait) => (
+                self.status(),
+                [(RETRY_AFTER, retry_after(wait).to_string())],
+                body,
+            )
+                .into_response(),
+            _ => (self.status(), body).into_response(),
+        }
+    }
+}
+
+impl From<QueryError> for AppError {
+    fn from(e: QueryError) -> Self {
+        match e {
+            QueryError::NotFound => Self::NotFound("Not found".to_string()),
+            QueryError::DatabaseError(
+                kind @ (DatabaseErrorKind::UniqueViolation
+                | DatabaseErrorKind::ForeignKeyViolation
+                | DatabaseErrorKind::CheckViolation
+                | DatabaseErrorKind::NotNullViolation),
+                info,
+            ) => Self::Constraint {
+                kind,
+                constraint: info.constraint_name().map(str::to_string),
+            },
+            e => internal_error(e),
+        }
+    }
+}
+
+impl From<DbError> for AppError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::Unavailable(e) => {
+                tracing::error!("Database unavailable: {e}");
+                Self::Unavailable
+            }
+            DbError::Query(e) => e.into(),
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Endpoints telling orchestrators and reverse proxies whether the server is up, and whether it can
+//! usefully serve requests.
+
+use std::{collections::BTreeMap, path::Path};
+
+use axum::{extract::State, http::StatusCode};
+use diesel::{prelude::*, sql_query};
+use diesel_migrations::MigrationHarness;
+use serde::Serialize;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::{
+    config,
+    db::{Db, DbResult},
+    extract::Json,
+    MIGRATIONS,
+};
+
+#[derive(Serialize)]
+pub struct Health {
+    status: &'static str,
+}
+
+/// Liveness: the process is running and handling requests.
+pub async fn healthz() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    status: &'static str,
+    /// `"ok"`, or what is wrong, for each check made. The details of errors are only logged, as
+    /// this is served to anyone.
+    checks: BTreeMap<&'static str, &'static str>,
+}
+
+/// Readiness: the database is reachable and fully migrated, uploads can be stored, and the server
+/// isn't shutting down. Responds with `503 Service Unavailable` if any of these fail.
+pub async fn readyz(
+    State(db): State<Db>,
+    State(shutting_down): State<watch::Receiver<bool>>,
+) -> (StatusCode, Json<Readiness>) {
+    let mut checks = BTreeMap::new();
+    if *shutting_down.borrow() {
+        checks.insert("shutdown", "Shutting down");
+    }
+    checks.insert("database", check_database(&db).await);
+    checks.insert("media", check_media(&config::get().server.media_path).await);
+
+    if checks.values().all(|check| *check == "ok") {
+        let status = "ready";
+        (StatusCode::OK, Json(Readiness { status, checks }))
+    } else {
+        let status = "unavailable";
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Readiness { status, checks }),
+        )
+    }
+}
+
+async fn check_database(db: &Db) -> &'static str {
+    let result = db
+        .run(|conn| {
+            sql_query("SELECT 1").execute(conn)?;
+            DbResult::Ok(
+                conn.has_pending_migration(MIGRATIONS)
+                    .map_err(|e| e.to_string()),
+            )
+        })
+        .await;
+    match result {
+        Ok(Ok(false)) => "ok",
+        Ok(Ok(true)) => "Migrations are pending",
+        Ok(Err(e)) => {
+            tracing::error!("Readiness check couldn't check migrations: {e}");
+            "unavailable"
+        }
+        Err(e) => {
+            tracing::error!("Readiness check couldn't reach the database: {e}");
+            "unavailable"
+        }
+    }
+}
+
+/// Check that a file can be written to the media directory, and removed again.
+async fn check_media(dir: &Path) -> &'static str {
+    let path = dir.join(format!(".readyz-{}", Uuid::new_v4()));
+    let result = async {
+        tokio::fs::write(&path, b"").await?;
+        tokio::fs::remove_file(&path).await
+    }
+    .await;
+    match result {
+        Ok(()) => "ok",
+        Err(e) => {
+            tracing::error!("Readiness check couldn't write to the media directory: {e}");
+            "unavailable"
+        }
+    }
+}
+
+/// Wait for the process to be asked to stop, by SIGTERM (e.g. from `docker stop`) or Ctrl+C.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
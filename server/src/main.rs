@@ -1,51 +1,101 @@
+mod admin;
+mod api_key;
 mod auth;
+mod browse;
+mod cli;
+mod config;
+mod db;
+mod email;
+mod error;
+mod extract;
+mod health;
+mod logging;
+mod mail;
+mod metrics;
+mod mfa;
 mod models;
+mod oidc;
+mod password;
+mod problem;
+mod revision;
 mod schema;
+mod search;
+mod session;
+mod solution;
+mod throttle;
+mod token;
 
-use std::{
-    cmp::Ordering, collections::HashMap, env, error::Error, fs, net::SocketAddr, path::PathBuf,
-    sync::Arc,
-};
+use std::{cmp::Ordering, collections::HashMap, fs, net::SocketAddr, process, sync::Arc};
 
 use axum::{
-    extract::Multipart,
+    extract::{FromRef, Multipart, State},
     http::{
         header::{AUTHORIZATION, CONTENT_TYPE},
-        HeaderMap, StatusCode,
+        HeaderMap, HeaderName, HeaderValue,
     },
     middleware,
-    response::Json,
-    routing::{get, post, put},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use chrono::{NaiveDateTime, Utc};
+use clap::Parser;
 use diesel::{dsl, pg::PgConnection, prelude::*, query_dsl::BelongingToDsl};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use itertools::Itertools;
 use rand::{distributions::WeightedIndex, prelude::*};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use tower_http::cors::{Any, CorsLayer};
+use tokio::sync::watch;
+use tower_http::cors::{AllowMethods, CorsLayer};
 
 use models::{Module, ModulesView, NewProblem, Problem, Topic};
 use uuid::Uuid;
 
-use crate::models::{AddModule, AddTopic, InsertModule, ProblemTopic, Solution, UserProblem};
+use crate::{
+    cli::{Cli, Command},
+    config::Config,
+    db::{Db, DbResult},
+    error::{internal_error, AppError},
+    extract::Json,
+    mail::Mailer,
+    metrics::Metrics,
+    models::{
+        AddModule, AddTopic, AddTopicIn, InsertModule, ProblemTopic, Role, Solution, UserProblem,
+    },
+    oidc::Oidc,
+    session::Sessions,
+    throttle::LoginThrottle,
+};
 
 // The migration path is relative to `CARGO_MANIFEST_DIR`.
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
 
+/// Open a connection outside of the pool, for the command line tools.
 pub fn establish_connection() -> PgConnection {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
+    let database_url = &config::get().database.url;
+    PgConnection::establish(database_url)
         .unwrap_or_else(|e| panic!("Error connecting to {database_url}\n {e}"))
 }
 
-fn extract_user_id(headers: &HeaderMap) -> Result<Uuid, (StatusCode, String)> {
+fn extract_user_id(headers: &HeaderMap) -> Result<Uuid, AppError> {
     headers
         .get("user_id")
-        .ok_or_else(|| (StatusCode::UNAUTHORIZED, "No user".to_string()))
-        .map(|id| Uuid::parse_str(id.to_str().unwrap()).unwrap())
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(|| AppError::Unauthorized("No user".to_string()))
+}
+
+/// State shared by all of the handlers.
+#[derive(Clone, FromRef)]
+struct AppState {
+    db: Db,
+    sessions: Sessions,
+    throttle: LoginThrottle,
+    mailer: Arc<dyn Mailer>,
+    /// Single sign-on, if configured.
+    oidc: Option<Arc<Oidc>>,
+    metrics: Metrics,
+    /// Set once the server has been asked to shut down.
+    shutting_down: watch::Receiver<bool>,
 }
 
 #[tokio::main]
@@ -55,81 +105,288 @@ async fn main() {
         dotenvy::dotenv().ok();
     }
 
-    let mut conn = establish_connection();
-    conn.run_pending_migrations(MIGRATIONS).unwrap();
-
-    // TODO: dotenv.
-    let origins = if cfg!(debug_assertions) {
-        vec![
-            "http://localhost:5173".parse().unwrap(),
-            "http://localhost:4173".parse().unwrap(),
-        ]
-    } else {
-        vec!["https://watson-project.com".parse().unwrap()]
+    let cli = Cli::parse();
+    match Config::load(cli.config.as_deref()) {
+        Ok(config) => {
+            logging::init(&config.log);
+            config::init(config);
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve().await,
+        command => {
+            if let Err(e) = cli::run(command) {
+                eprintln!("Error: {e}");
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Run the HTTP server.
+async fn serve() {
+    let config = config::get();
+    if let Err(e) = fs::create_dir_all(&config.server.media_path) {
+        tracing::error!(
+            "Error creating media directory {}: {e}",
+            config.server.media_path.display()
+        );
+        process::exit(1);
+    }
+
+    let db = Db::new(config.database.clone());
+    if let Err(e) = db
+        .run(|conn| conn.run_pending_migrations(MIGRATIONS).map(|_| ()))
+        .await
+    {
+        tracing::error!("Error applying migrations: {e}");
+        process::exit(1);
+    }
+
+    // The origins were checked when the configuration was loaded.
+    let origins: Vec<HeaderValue> = config
+        .server
+        .cors_origins
+        .iter()
+        .map(|origin| origin.parse().unwrap())
+        .collect();
+
+    let sessions = Sessions::new(db.clone(), config.session);
+    sessions.spawn_purge_task();
+    let throttle = LoginThrottle::new(db.clone(), config.login_throttle);
+    throttle.spawn_purge_task();
+    let (shutdown, shutting_down) = watch::channel(false);
+    let state = AppState {
+        db,
+        sessions,
+        throttle,
+        mailer: mail::from_config(&config.mail),
+        oidc: config
+            .oidc
+            .clone()
+            .map(|config| Arc::new(Oidc::new(config))),
+        metrics: Metrics::new(),
+        shutting_down: shutting_down.clone(),
     };
 
-    let sessions = Arc::new(RwLock::new(HashMap::new()));
+    if let Some(addr) = config.metrics.bind {
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics::export))
+            .with_state(state.clone());
+        let listener = listen(addr).await;
+        tracing::info!("Serving metrics on {addr}");
+        let shutting_down = shutting_down.clone();
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, metrics_app)
+                .with_graceful_shutdown(wait_for_shutdown(shutting_down))
+                .await
+            {
+                tracing::error!("Error serving metrics: {e}");
+            }
+        });
+    }
 
-    let app = Router::new()
+    // Routes managing the account itself, which can't be used with an API key.
+    let account = Router::new()
+        .route("/logout", post(auth::logout))
+        .route("/sessions", get(auth::list_sessions))
+        .route("/sessions/:id", delete(auth::revoke_session))
+        .route("/password", put(password::change_password))
+        .route("/email", put(email::change_email))
+        .route("/email/resend", post(email::resend_verification))
+        .route("/mfa/totp", delete(mfa::disable))
+        .route("/mfa/totp/enrol", post(mfa::enrol))
+        .route("/mfa/totp/confirm", post(mfa::confirm))
+        .route("/mfa/recovery-codes", post(mfa::regenerate_recovery_codes))
+        .route(
+            "/api-keys",
+            get(api_key::list_keys).post(api_key::create_key),
+        )
+        .route("/api-keys/:id", delete(api_key::revoke_key))
+        .route_layer(middleware::from_fn(auth::require_session));
+
+    let admin = Router::new()
+        .route("/admin/users/:id/role", put(admin::set_role))
+        .route("/admin/users/:id/unlock", post(admin::unlock_user))
+        .route(
+            "/admin/tokens",
+            get(admin::list_tokens).post(admin::create_token),
+        )
+        .route("/admin/tokens/:id", delete(admin::revoke_token))
+        .route_layer(middleware::from_fn_with_state(
+            Role::Admin,
+            auth::require_role,
+        ));
+
+    let mut app = Router::new()
+        .route("/problems", get(browse::list_problems))
+        .route("/search", get(search::search))
         .route("/problems/create", post(create_problem))
         .route("/problems/request", post(request_problem))
         .route("/problems/solve", put(solve_problem))
+        .route(
+            "/problems/:id",
+            get(problem::get_problem)
+                .patch(problem::edit_problem)
+                .delete(problem::delete_problem),
+        )
+        .route("/problems/:id/restore", post(problem::restore_problem))
+        .route(
+            "/problems/:id/revisions",
+            get(revision::list_problem_revisions),
+        )
+        .route(
+            "/problems/:id/topics",
+            post(problem::add_topics).put(problem::set_topics),
+        )
+        .route(
+            "/problems/:id/topics/:topic_id",
+            delete(problem::remove_topic),
+        )
+        .route("/problems/:id/diff", get(revision::diff_problem))
+        .route("/problems/:id/rollback", post(revision::rollback_problem))
         .route("/solutions", post(submit_solution))
+        .route("/solutions/:id", patch(solution::edit_solution))
+        .route(
+            "/solutions/:id/revisions",
+            get(revision::list_solution_revisions),
+        )
+        .route("/solutions/:id/diff", get(revision::diff_solution))
+        .route("/solutions/:id/rollback", post(revision::rollback_solution))
         .route("/modules", get(get_modules))
         .route("/leaderboard", get(get_leaderboard))
         .route("/upload", post(upload))
-        .route_layer(middleware::from_fn_with_state(
-            Arc::clone(&sessions),
-            auth::auth,
-        ))
+        .merge(account)
+        .merge(admin)
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::auth))
         .route("/login", post(auth::login))
+        .route("/login/mfa", post(mfa::login))
+        .route("/oidc/login", get(oidc::login))
+        .route("/oidc/callback", get(oidc::callback))
+        .route("/refresh", post(auth::refresh))
         .route("/register", post(auth::register))
+        .route("/password-reset", post(password::request_reset))
+        .route("/password-reset/confirm", post(password::confirm_reset))
+        .route("/email/verify", post(email::verify_email))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz));
+    // Without their own listener, metrics are only served to those with the token.
+    if config.metrics.bind.is_none() && config.metrics.token.is_some() {
+        app = app.route("/metrics", get(metrics::export));
+    }
+    let app = app
+        .layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
         .layer(
             CorsLayer::new()
-                .allow_methods(Any)
-                .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+                .allow_methods(AllowMethods::mirror_request())
+                .allow_headers([
+                    CONTENT_TYPE,
+                    AUTHORIZATION,
+                    HeaderName::from_static(auth::CSRF_HEADER),
+                ])
+                .expose_headers([logging::REQUEST_ID_HEADER])
+                .allow_credentials(true)
                 .allow_origin(origins),
         )
-        .with_state(sessions);
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    println!("Listening on {addr}");
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .with_state(state);
+    let app = logging::layer(app);
+    let addr = config.server.bind;
+    let listener = listen(addr).await;
+    tracing::info!("Listening on {addr}");
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(wait_for_shutdown(shutting_down));
+    let mut server = tokio::spawn(async move { server.await });
+
+    tokio::select! {
+        result = &mut server => {
+            if let Ok(Err(e)) = result {
+                tracing::error!("Error serving requests: {e}");
+            }
+            process::exit(1);
+        }
+        () = health::shutdown_signal() => {}
+    }
+
+    // Stop accepting connections, and give those open a while to finish.
+    tracing::info!("Shutting down");
+    shutdown.send_replace(true);
+    let timeout = config.server.shutdown_timeout;
+    match tokio::time::timeout(timeout, server).await {
+        Ok(_) => tracing::info!("Shut down"),
+        Err(_) => tracing::warn!(
+            "Requests still in progress after {} seconds, stopping anyway",
+            timeout.as_secs()
+        ),
+    }
+}
+
+/// Wait until the server is asked to shut down.
+async fn wait_for_shutdown(mut shutting_down: watch::Receiver<bool>) {
+    // This only fails if the sender is dropped, which also means the server is stopping.
+    let _ = shutting_down.wait_for(|shutting_down| *shutting_down).await;
+}
+
+/// Bind a listener to `addr`, exiting if that isn't possible.
+async fn listen(addr: SocketAddr) -> tokio::net::TcpListener {
+    tokio::net::TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Error listening on {addr}: {e}");
+            process::exit(1);
+        })
 }
 
 /// Endpoint to upload a file (image) which is then stored on the server.
-async fn upload(mut multipart: Multipart) -> Result<String, (StatusCode, String)> {
+async fn upload(
+    State(metrics): State<Metrics>,
+    mut multipart: Multipart,
+) -> Result<String, AppError> {
     // For now, you can only upload one file at a time.
-    let Some(field) = multipart.next_field().await.map_err(internal_error)? else {
-        return Err((StatusCode::BAD_REQUEST, "No file uploaded.".to_string()));
+    let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::BadRequest(e.body_text()))?
+    else {
+        return Err(AppError::BadRequest("No file uploaded.".to_string()));
     };
 
     let name = Uuid::new_v4().to_string() + field.file_name().unwrap_or("");
-    let data = field.bytes().await.map_err(internal_error)?;
+    let data = field
+        .bytes()
+        .await
+        .map_err(|e| AppError::BadRequest(e.body_text()))?;
 
-    let store_path: PathBuf = [
-        env::var("MEDIA_PATH").expect("MEDIA_PATH must be set"),
-        name.clone(),
-    ]
-    .iter()
-    .collect();
+    let store_path = config::get().server.media_path.join(&name);
 
+    let size = data.len();
     fs::write(store_path.clone(), data).map_err(internal_error)?;
+    metrics.uploaded(size);
 
     Ok(name)
 }
 
-async fn get_modules() -> Result<Json<ModulesView>, (StatusCode, String)> {
+async fn get_modules(State(db): State<Db>) -> Result<Json<ModulesView>, AppError> {
     use schema::{modules, topics};
-    let mut conn = establish_connection();
-    let modules = modules::table
-        .select(Module::as_select())
-        .get_results(&mut conn)
-        .map_err(internal_error)?;
-    let topics = topics::table
-        .select(Topic::as_select())
-        .get_results(&mut conn)
-        .map_err(internal_error)?;
+    let (modules, topics) = db
+        .run(|conn| {
+            let modules = modules::table
+                .select(Module::as_select())
+                .get_results(conn)?;
+            let topics = topics::table.select(Topic::as_select()).get_results(conn)?;
+            DbResult::Ok((modules, topics))
+        })
+        .await?;
 
     Ok(Json(ModulesView { modules, topics }))
 }
@@ -141,25 +398,28 @@ struct LeaderboardEntry {
     n_solutions: i64,
 }
 
-async fn get_leaderboard() -> Result<Json<Vec<LeaderboardEntry>>, (StatusCode, String)> {
+async fn get_leaderboard(State(db): State<Db>) -> Result<Json<Vec<LeaderboardEntry>>, AppError> {
     const SOLUTIONS_WEIGHT: i64 = 2; // Solutions are worth this much more than problems in the
                                      // ranking.
 
     use schema::{problems, solutions, users};
-    let mut conn = establish_connection();
-
-    let n_problems = problems::table
-        .inner_join(users::table)
-        .group_by(users::id)
-        .select((users::id, users::name, dsl::count(problems::id)))
-        .load(&mut conn)
-        .map_err(internal_error)?;
-    let n_solutions = solutions::table
-        .inner_join(users::table)
-        .group_by(users::id)
-        .select((users::id, users::name, dsl::count(solutions::id)))
-        .load(&mut conn)
-        .map_err(internal_error)?;
+
+    let (n_problems, n_solutions) = db
+        .run(|conn| {
+            let n_problems = problems::table
+                .filter(problems::deleted_at.is_null())
+                .inner_join(users::table)
+                .group_by(users::id)
+                .select((users::id, users::name, dsl::count(problems::id)))
+                .load(conn)?;
+            let n_solutions = solutions::table
+                .inner_join(users::table)
+                .group_by(users::id)
+                .select((users::id, users::name, dsl::count(solutions::id)))
+                .load(conn)?;
+            DbResult::Ok((n_problems, n_solutions))
+        })
+        .await?;
 
     let mut scores_map: HashMap<Uuid, (String, i64, i64)> = HashMap::new();
     n_problems.into_iter().for_each(|(id, name, count)| {
@@ -199,73 +459,133 @@ struct SubmitSolution {
 }
 
 async fn submit_solution(
+    State(db): State<Db>,
     headers: HeaderMap,
     Json(solution): Json<SubmitSolution>,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<(), AppError> {
     use schema::solutions::*;
     let req_user_id = extract_user_id(&headers)?;
-    let mut conn = establish_connection();
-    diesel::insert_into(table)
-        .values((solution, user_id.eq(req_user_id)))
-        .execute(&mut conn)
-        .map_err(internal_error)?;
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            // Keeps the problem from being deleted while the solution is added.
+            problem::lock(conn, solution.problem_id)?;
+            let solution = diesel::insert_into(table)
+                .values((solution, user_id.eq(req_user_id)))
+                .returning(Solution::as_returning())
+                .get_result(conn)?;
+            revision::record_solution(conn, &solution, Some(req_user_id))?;
+            Ok::<_, AppError>(())
+        })
+    })
+    .await?;
     Ok(())
 }
 
 async fn create_problem(
+    State(db): State<Db>,
     headers: HeaderMap,
     Json(new_problem): Json<NewProblem>,
-) -> Result<Json<Problem>, (StatusCode, String)> {
-    use schema::{modules, problem_topic, problems, solutions, topics};
+) -> Result<Json<Problem>, AppError> {
     let user_id = extract_user_id(&headers)?;
-    let mut conn = establish_connection();
 
-    assert!(new_problem.problem.body.is_some() || new_problem.problem.img_path.is_some());
+    if new_problem.problem.body.is_none() && new_problem.problem.img_path.is_none() {
+        return Err(AppError::BadRequest(
+            "A problem must have a body or an image".to_string(),
+        ));
+    }
+
+    let result = db
+        .run(move |conn| conn.transaction(|conn| insert_problem(conn, Some(user_id), new_problem)))
+        .await?;
 
-    let module_id = match new_problem.module {
-        AddModule::Existing(id) => id,
-        AddModule::New(title) => diesel::insert_into(modules::table)
-            .values(InsertModule { title })
-            .returning(modules::id)
-            .get_result(&mut conn)
-            .map_err(internal_error)?,
+    Ok(Json(result))
+}
+
+/// Insert a problem, along with its solution and any new modules or topics it belongs to.
+pub fn insert_problem(
+    conn: &mut PgConnection,
+    user_id: Option<Uuid>,
+    new_problem: NewProblem,
+) -> Result<Problem, AppError> {
+    use schema::{modules, problems, solutions, topics};
+
+    // New modules are only created once, however many new topics are in them.
+    let mut new_modules: HashMap<String, i32> = HashMap::new();
+    let mut module_id = |conn: &mut PgConnection, module: AddModule| match module {
+        AddModule::Existing(id) => Ok(id),
+        AddModule::New(title) => match new_modules.get(&title) {
+            Some(&id) => Ok(id),
+            None => {
+                let id = diesel::insert_into(modules::table)
+                    .values(InsertModule {
+                        title: title.clone(),
+                    })
+                    .returning(modules::id)
+                    .get_result(conn)?;
+                new_modules.insert(title, id);
+                QueryResult::Ok(id)
+            }
+        },
     };
-    let topic_id = match new_problem.topic {
-        AddTopic::Existing(id) => id,
-        AddTopic::New(title) => diesel::insert_into(topics::table)
+    let insert_topic = |conn: &mut PgConnection, module_id: i32, title: String| {
+        diesel::insert_into(topics::table)
             .values((topics::module_id.eq(module_id), topics::title.eq(title)))
             .returning(topics::id)
-            .get_result(&mut conn)
-            .map_err(internal_error)?,
+            .get_result::<i32>(conn)
     };
 
+    let mut topic_ids = Vec::new();
+    match (new_problem.topic, new_problem.module) {
+        (Some(AddTopic::Existing(id)), _) => topic_ids.push(id),
+        (Some(AddTopic::New(title)), Some(module)) => {
+            let module_id = module_id(conn, module)?;
+            topic_ids.push(insert_topic(conn, module_id, title)?);
+        }
+        (Some(AddTopic::New(_)), None) => {
+            return Err(AppError::BadRequest(
+                "A new topic needs a module".to_string(),
+            ))
+        }
+        (None, _) => {}
+    }
+    for topic in new_problem.topics {
+        match topic {
+            AddTopicIn::Existing(id) => topic_ids.push(id),
+            AddTopicIn::New { module, title } => {
+                let module_id = module_id(conn, module)?;
+                topic_ids.push(insert_topic(conn, module_id, title)?);
+            }
+        }
+    }
+    if topic_ids.is_empty() {
+        return Err(AppError::BadRequest(
+            "A problem must have at least one topic".to_string(),
+        ));
+    }
+    let topic_ids = problem::check_topics_exist(conn, &topic_ids)?;
+
     let result = diesel::insert_into(problems::table)
         .values((&new_problem.problem, problems::user_id.eq(user_id)))
         .returning(Problem::as_returning())
-        .get_result(&mut conn)
-        .map_err(internal_error)?;
+        .get_result(conn)?;
+    revision::record_problem(conn, &result, user_id)?;
 
     if new_problem.soln.is_some() || new_problem.soln_img.is_some() {
-        diesel::insert_into(solutions::table)
+        let solution = diesel::insert_into(solutions::table)
             .values((
                 solutions::body.eq(new_problem.soln),
                 solutions::img_path.eq(new_problem.soln_img),
                 solutions::problem_id.eq(result.id),
                 solutions::user_id.eq(user_id),
             ))
-            .execute(&mut conn)
-            .map_err(internal_error)?;
+            .returning(Solution::as_returning())
+            .get_result(conn)?;
+        revision::record_solution(conn, &solution, user_id)?;
     }
 
-    diesel::insert_into(problem_topic::table)
-        .values((
-            problem_topic::problem_id.eq(result.id),
-            problem_topic::topic_id.eq(topic_id),
-        ))
-        .execute(&mut conn)
-        .map_err(internal_error)?;
+    problem::attach_topics(conn, result.id, &topic_ids)?;
 
-    Ok(Json(result))
+    Ok(result)
 }
 
 #[derive(Deserialize)]
@@ -275,30 +595,35 @@ struct SolveProblem {
 }
 
 async fn solve_problem(
+    State(db): State<Db>,
+    State(metrics): State<Metrics>,
     headers: HeaderMap,
     Json(SolveProblem {
         problem_id,
         successful,
     }): Json<SolveProblem>,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<(), AppError> {
     use schema::user_problem;
     let user_id = extract_user_id(&headers)?;
-    let mut conn = establish_connection();
-    diesel::insert_into(user_problem::table)
-        .values(UserProblem {
-            user_id,
-            problem_id,
-            last_solved: Utc::now().naive_utc(),
-            successful,
-        })
-        .on_conflict((user_problem::user_id, user_problem::problem_id))
-        .do_update()
-        .set((
-            user_problem::last_solved.eq(Utc::now().naive_utc()),
-            user_problem::successful.eq(successful),
-        ))
-        .execute(&mut conn)
-        .map_err(internal_error)?;
+    db.run(move |conn| {
+        diesel::insert_into(user_problem::table)
+            .values(UserProblem {
+                user_id,
+                problem_id,
+                last_solved: Utc::now().naive_utc(),
+                successful,
+            })
+            .on_conflict((user_problem::user_id, user_problem::problem_id))
+            .do_update()
+            .set((
+                user_problem::last_solved.eq(Utc::now().naive_utc()),
+                user_problem::successful.eq(successful),
+            ))
+            .execute(conn)?;
+        DbResult::Ok(())
+    })
+    .await?;
+    metrics.problem_attempted(successful);
 
     Ok(())
 }
@@ -315,147 +640,169 @@ struct ProblemResponse {
     solution_img: Option<String>,
 }
 
+/// A problem a user could be served, alongside the topic it was found under and the user's last
+/// attempt at it (if any).
+type CandidateProblem = (i32, (Option<NaiveDateTime>, Option<bool>), Problem);
+
 async fn request_problem(
+    State(db): State<Db>,
+    State(metrics): State<Metrics>,
     headers: HeaderMap,
     Json(request): Json<ProblemRequest>,
-) -> Result<Json<ProblemResponse>, (StatusCode, String)> {
+) -> Result<Json<ProblemResponse>, AppError> {
     use schema::{problem_topic, problems, solutions, topics, user_problem, users};
-    let mut conn = establish_connection();
     let user_id = extract_user_id(&headers)?;
 
-    let selected_topics: Vec<Topic> = match request.topic_ids.len() {
-        0 => topics::table.load(&mut conn),
-        _ => topics::table
-            .filter(topics::id.eq_any(&request.topic_ids))
-            .load(&mut conn),
-    }
-    .map_err(internal_error)?;
-    let mut valid_problems: Vec<(i32, (Option<NaiveDateTime>, Option<bool>), Problem)> =
-        ProblemTopic::belonging_to(&selected_topics)
-            .inner_join(problems::table.left_join(user_problem::table.inner_join(users::table)))
-            .filter(users::id.eq(user_id).or(users::id.is_null()))
-            .select((
-                problem_topic::topic_id,
-                (
-                    // TODO: I don't remember why these have to be optional values.
-                    user_problem::last_solved.nullable(),
-                    user_problem::successful.nullable(),
-                ),
-                Problem::as_select(),
-            ))
-            .load(&mut conn)
-            .map_err(internal_error)?;
-
-    valid_problems.sort_by(|(_, (user1, _), problem1), (_, (user2, _), problem2)| {
-        match problem1.id.cmp(&problem2.id) {
-            Ordering::Less => Ordering::Less,
-            Ordering::Greater => Ordering::Greater,
-            Ordering::Equal => match (user1.is_some(), user2.is_some()) {
-                (true, false) => Ordering::Less,
-                (false, true) => Ordering::Greater,
-                _ => Ordering::Equal, // This *should* be unreachable!
-            },
-        }
-    });
-    valid_problems.dedup_by_key(|(_, _, problem)| problem.id);
-
-    let mut topic_problems_map: HashMap<i32, Vec<Problem>> = HashMap::new();
-    for (topic_id, (last_solved, successful), problem) in valid_problems {
-        if let Some(last_solved) = last_solved {
-            // Reject this problem if we already saw it too recently.
-            if Utc::now()
-                .naive_utc()
-                .signed_duration_since(last_solved)
-                .num_weeks()
-                < if successful.unwrap() { 4 } else { 1 }
-            {
-                continue;
+    let response = db
+        .run(move |conn| {
+            // No topics means any topic.
+            let selected_topics: Vec<Topic> = match request.topic_ids.len() {
+                0 => topics::table.load(conn)?,
+                _ => {
+                    let topic_ids = problem::check_topics_exist(conn, &request.topic_ids)?;
+                    topics::table
+                        .filter(topics::id.eq_any(topic_ids))
+                        .load(conn)?
+                }
+            };
+            if selected_topics.is_empty() {
+                return Ok(ProblemResponse {
+                    problem: None,
+                    solution: None,
+                    solution_img: None,
+                });
+            }
+            let mut valid_problems: Vec<CandidateProblem> =
+                ProblemTopic::belonging_to(&selected_topics)
+                    .inner_join(
+                        problems::table.left_join(user_problem::table.inner_join(users::table)),
+                    )
+                    .filter(users::id.eq(user_id).or(users::id.is_null()))
+                    .filter(problems::deleted_at.is_null())
+                    .select((
+                        problem_topic::topic_id,
+                        (
+                            // TODO: I don't remember why these have to be optional values.
+                            user_problem::last_solved.nullable(),
+                            user_problem::successful.nullable(),
+                        ),
+                        Problem::as_select(),
+                    ))
+                    .load(conn)?;
+
+            valid_problems.sort_by(|(_, (user1, _), problem1), (_, (user2, _), problem2)| {
+                match problem1.id.cmp(&problem2.id) {
+                    Ordering::Less => Ordering::Less,
+                    Ordering::Greater => Ordering::Greater,
+                    Ordering::Equal => match (user1.is_some(), user2.is_some()) {
+                        (true, false) => Ordering::Less,
+                        (false, true) => Ordering::Greater,
+                        _ => Ordering::Equal, // This *should* be unreachable!
+                    },
+                }
+            });
+            valid_problems.dedup_by_key(|(_, _, problem)| problem.id);
+
+            let mut topic_problems_map: HashMap<i32, Vec<Problem>> = HashMap::new();
+            let revision = config::get().revision;
+            for (topic_id, (last_solved, successful), problem) in valid_problems {
+                if let Some(last_solved) = last_solved {
+                    // Reject this problem if we already saw it too recently.
+                    if Utc::now().naive_utc().signed_duration_since(last_solved)
+                        < if successful.unwrap() {
+                            revision.after_success
+                        } else {
+                            revision.after_failure
+                        }
+                    {
+                        continue;
+                    }
+                }
+
+                if let Some(ps) = topic_problems_map.get_mut(&topic_id) {
+                    ps.push(problem);
+                } else {
+                    topic_problems_map.insert(topic_id, vec![problem]);
+                }
             }
-        }
-
-        if let Some(ps) = topic_problems_map.get_mut(&topic_id) {
-            ps.push(problem);
-        } else {
-            topic_problems_map.insert(topic_id, vec![problem]);
-        }
-    }
 
-    // Now we hopefully have only one of every problem!
-    // Next, we have to find the user's success rate for each topic.
-
-    let n_incorrect: Vec<(i32, i64)> = ProblemTopic::belonging_to(&selected_topics)
-        .inner_join(problems::table.left_join(user_problem::table.inner_join(users::table)))
-        .filter(users::id.eq(user_id))
-        .filter(diesel::dsl::not(user_problem::successful))
-        .group_by(problem_topic::topic_id)
-        .select((problem_topic::topic_id, diesel::dsl::count(problems::id)))
-        .load(&mut conn)
-        .map_err(internal_error)?;
-    let n_total: Vec<(i32, i64)> = ProblemTopic::belonging_to(&selected_topics)
-        .inner_join(problems::table.left_join(user_problem::table.inner_join(users::table)))
-        .filter(users::id.eq(user_id))
-        .group_by(problem_topic::topic_id)
-        .select((problem_topic::topic_id, diesel::dsl::count(problems::id)))
-        .load(&mut conn)
-        .map_err(internal_error)?;
-
-    // Laplace's rule of succession.
-    let mut laplace_weights = Vec::new();
-    for id_k in &request.topic_ids {
-        let numerator = n_incorrect
-            .iter()
-            .find(|(id, _)| *id == *id_k)
-            .map(|(_, n)| *n)
-            .unwrap_or(0) as f64
-            + 1.0;
-        let denominator = n_total
-            .iter()
-            .find(|(id, _)| *id == *id_k)
-            .map(|(_, n)| *n)
-            .unwrap_or(0) as f64
-            + 2.0;
-        laplace_weights.push(numerator / denominator);
-    }
+            // Now we hopefully have only one of every problem!
+            // Next, we have to find the user's success rate for each topic.
+
+            let n_incorrect: Vec<(i32, i64)> = ProblemTopic::belonging_to(&selected_topics)
+                .inner_join(problems::table.left_join(user_problem::table.inner_join(users::table)))
+                .filter(users::id.eq(user_id))
+                .filter(diesel::dsl::not(user_problem::successful))
+                .group_by(problem_topic::topic_id)
+                .select((problem_topic::topic_id, diesel::dsl::count(problems::id)))
+                .load(conn)?;
+            let n_total: Vec<(i32, i64)> = ProblemTopic::belonging_to(&selected_topics)
+                .inner_join(problems::table.left_join(user_problem::table.inner_join(users::table)))
+                .filter(users::id.eq(user_id))
+                .group_by(problem_topic::topic_id)
+                .select((problem_topic::topic_id, diesel::dsl::count(problems::id)))
+                .load(conn)?;
+
+            // Laplace's rule of succession.
+            let mut laplace_weights = Vec::new();
+            for Topic { id: id_k, .. } in &selected_topics {
+                let numerator = n_incorrect
+                    .iter()
+                    .find(|(id, _)| *id == *id_k)
+                    .map(|(_, n)| *n)
+                    .unwrap_or(0) as f64
+                    + 1.0;
+                let denominator = n_total
+                    .iter()
+                    .find(|(id, _)| *id == *id_k)
+                    .map(|(_, n)| *n)
+                    .unwrap_or(0) as f64
+                    + 2.0;
+                laplace_weights.push(numerator / denominator);
+            }
 
-    let dist = WeightedIndex::new(&laplace_weights).map_err(internal_error)?;
-    let mut rng = thread_rng();
-    let next_problem = loop {
-        let next_topic = request.topic_ids[dist.sample(&mut rng)];
-        if let Some(problem) = topic_problems_map.get_mut(&next_topic).and_then(|topic| {
-            (!topic.is_empty()).then(|| {
-                let next_problem_idx: usize = rng.gen_range(0..topic.len());
-                topic.swap_remove(next_problem_idx)
+            let dist = WeightedIndex::new(&laplace_weights).map_err(|e| {
+                AppError::Unprocessable(format!("Can't choose between the topics: {e}"))
+            })?;
+            let mut rng = thread_rng();
+            let next_problem = loop {
+                let next_topic = selected_topics[dist.sample(&mut rng)].id;
+                if let Some(problem) = topic_problems_map.get_mut(&next_topic).and_then(|topic| {
+                    (!topic.is_empty()).then(|| {
+                        let next_problem_idx: usize = rng.gen_range(0..topic.len());
+                        topic.swap_remove(next_problem_idx)
+                    })
+                }) {
+                    break Some(problem.to_owned());
+                }
+                if topic_problems_map
+                    .values()
+                    .map(|ps| ps.len())
+                    .sum::<usize>()
+                    == 0
+                {
+                    break None;
+                }
+            };
+
+            let (solution, solution_img): (Option<String>, Option<String>) = next_problem
+                .as_ref()
+                .and_then(|problem| {
+                    Solution::belonging_to(problem)
+                        .select((solutions::body.nullable(), solutions::img_path.nullable()))
+                        .first(conn)
+                        .ok()
+                })
+                .unwrap_or((None, None));
+
+            Ok::<_, AppError>(ProblemResponse {
+                problem: next_problem,
+                solution,
+                solution_img,
             })
-        }) {
-            break Some(problem.to_owned());
-        }
-        if topic_problems_map
-            .values()
-            .map(|ps| ps.len())
-            .sum::<usize>()
-            == 0
-        {
-            break None;
-        }
-    };
-
-    let (solution, solution_img): (Option<String>, Option<String>) = next_problem
-        .as_ref()
-        .and_then(|problem| {
-            Solution::belonging_to(problem)
-                .select((solutions::body.nullable(), solutions::img_path.nullable()))
-                .first(&mut conn)
-                .ok()
         })
-        .unwrap_or((None, None));
-
-    Ok(Json(ProblemResponse {
-        problem: next_problem,
-        solution,
-        solution_img,
-    }))
-}
+        .await?;
+    metrics.problem_requested(response.problem.is_some());
 
-pub fn internal_error<E: Error>(error: E) -> (StatusCode, String) {
-    (StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    Ok(Json(response))
 }
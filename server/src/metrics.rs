@@ -0,0 +1,214 @@
+//! Prometheus metrics about requests, the database and how the service is used.
+
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config,
+    db::Db,
+    error::{internal_error, AppError},
+    session::Sessions,
+};
+
+/// Where metrics are served. They are only served if at least one of these is set.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve `/metrics` on its own listener at this address, rather than alongside the API.
+    pub bind: Option<SocketAddr>,
+    /// Require scrapes to send this in an `Authorization: Bearer` header.
+    pub token: Option<String>,
+}
+
+/// The metrics collected by the server.
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+
+struct Inner {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    db_pool_connections: IntGauge,
+    db_pool_idle_connections: IntGauge,
+    active_sessions: IntGauge,
+    problem_requests: IntCounterVec,
+    problem_attempts: IntCounterVec,
+    uploads: IntCounter,
+    upload_bytes: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        fn register<T: prometheus::core::Collector + Clone + 'static>(
+            registry: &Registry,
+            metric: prometheus::Result<T>,
+        ) -> T {
+            let metric = metric.expect("metric must be valid");
+            registry
+                .register(Box::new(metric.clone()))
+                .expect("metric must only be registered once");
+            metric
+        }
+
+        let registry = Registry::new();
+        let inner = Inner {
+            http_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("http_requests_total", "HTTP requests handled."),
+                    &["method", "route", "status"],
+                ),
+            ),
+            http_request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_request_duration_seconds",
+                        "Time taken to handle HTTP requests.",
+                    ),
+                    &["method", "route"],
+                ),
+            ),
+            db_pool_connections: register(
+                &registry,
+                IntGauge::new(
+                    "db_pool_connections",
+                    "Open database connections, whether in use or idle.",
+                ),
+            ),
+            db_pool_idle_connections: register(
+                &registry,
+                IntGauge::new("db_pool_idle_connections", "Idle database connections."),
+            ),
+            active_sessions: register(
+                &registry,
+                IntGauge::new("active_sessions", "Sessions which haven't expired."),
+            ),
+            problem_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "problem_requests_total",
+                        "Problems requested, by whether one could be served.",
+                    ),
+                    &["outcome"],
+                ),
+            ),
+            problem_attempts: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "problem_attempts_total",
+                        "Attempts at problems recorded, by whether they were successful.",
+                    ),
+                    &["result"],
+                ),
+            ),
+            uploads: register(
+                &registry,
+                IntCounter::new("uploads_total", "Files uploaded."),
+            ),
+            upload_bytes: register(
+                &registry,
+                IntCounter::new("upload_bytes_total", "Total size of files uploaded."),
+            ),
+            registry,
+        };
+        Self(Arc::new(inner))
+    }
+
+    /// Record that a problem was requested, and whether there was one to serve.
+    pub fn problem_requested(&self, served: bool) {
+        let outcome = if served { "served" } else { "empty" };
+        self.0.problem_requests.with_label_values(&[outcome]).inc();
+    }
+
+    /// Record that a user attempted a problem.
+    pub fn problem_attempted(&self, successful: bool) {
+        let result = if successful { "success" } else { "failure" };
+        self.0.problem_attempts.with_label_values(&[result]).inc();
+    }
+
+    /// Record that a file of `bytes` bytes was uploaded.
+    pub fn uploaded(&self, bytes: usize) {
+        self.0.uploads.inc();
+        self.0.upload_bytes.inc_by(bytes as u64);
+    }
+}
+
+/// Middleware counting requests and timing them, by the route which handled them.
+pub async fn track(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    // Unmatched paths are lumped together, so that clients can't create any number of series.
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics
+        .0
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .0
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Serve the metrics in the Prometheus text format.
+pub async fn export(
+    State(metrics): State<Metrics>,
+    State(db): State<Db>,
+    State(sessions): State<Sessions>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(token) = &config::get().metrics.token {
+        let given = headers
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .unwrap_or("");
+        if !bool::from(given.as_bytes().ct_eq(token.as_bytes())) {
+            return Err(AppError::Unauthorized("Invalid metrics token".to_string()));
+        }
+    }
+
+    let pool = db.state();
+    metrics.0.db_pool_connections.set(pool.connections.into());
+    metrics
+        .0
+        .db_pool_idle_connections
+        .set(pool.idle_connections.into());
+    metrics
+        .0
+        .active_sessions
+        .set(sessions.count_active().await?);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&metrics.0.registry.gather(), &mut body)
+        .map_err(internal_error)?;
+    Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response())
+}
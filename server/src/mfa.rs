@@ -0,0 +1,428 @@
+use axum::{extract::State, http::HeaderMap, response::Response};
+use axum_extra::extract::cookie::CookieJar;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, result::Error};
+use rand::{distributions::Slice, rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::{
+    auth::{confirm_password, session_response},
+    db::{Db, DbError, DbResult},
+    error::AppError,
+    extract::Json,
+    extract_user_id,
+    session::{ClientInfo, Sessions},
+    throttle::LoginThrottle,
+    token,
+};
+
+/// Shown as the account's provider in authenticator apps.
+const ISSUER: &str = "Watson";
+
+/// Length of a TOTP time step, in seconds.
+const TOTP_STEP: u64 = 30;
+
+/// How long a user has to enter their second factor after giving their password.
+const CHALLENGE_MINUTES: i64 = 5;
+
+const RECOVERY_CODES: usize = 10;
+
+/// Recovery codes are written down or printed, so avoid characters which look alike.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// The TOTP generator for a user's secret. An invalid secret can only come from a corrupt
+/// database, so is reported as a deserialization error.
+fn totp(secret: &str, email: String) -> QueryResult<TOTP> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| Error::DeserializationError(e.into()))?;
+    // Allowing for clock skew is done in `check_totp`, so that we know which step matched.
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(ISSUER.to_string()),
+        email,
+    )
+    .map_err(|e| Error::DeserializationError(e.into()))
+}
+
+/// Check a TOTP code, allowing for the clocks being a step apart. Codes from `last_step` or
+/// earlier are rejected, so that each code can only be used once. Returns the step which matched.
+fn check_totp(totp: &TOTP, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let current_step = Utc::now().timestamp() / TOTP_STEP as i64;
+    (current_step - 1..=current_step + 1)
+        .filter(|&step| last_step.map_or(true, |last_step| step > last_step))
+        .find(|&step| totp.check(code, step as u64 * TOTP_STEP))
+}
+
+fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Replace a user's recovery codes with new ones, returning them. Only their hashes are stored.
+fn new_recovery_codes(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<Vec<String>> {
+    use crate::schema::recovery_codes;
+    let alphabet = Slice::new(RECOVERY_CODE_ALPHABET).unwrap();
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let code: String = OsRng
+                .sample_iter(alphabet)
+                .take(10)
+                .map(|&c| c as char)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+        .execute(conn)?;
+    diesel::insert_into(recovery_codes::table)
+        .values(
+            codes
+                .iter()
+                .map(|code| {
+                    (
+                        recovery_codes::user_id.eq(user_id),
+                        recovery_codes::code_hash.eq(token::hash(&normalise_recovery_code(code))),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(conn)?;
+    Ok(codes)
+}
+
+/// Check a second factor for a user with two-factor authentication enabled: either a TOTP code,
+/// or a recovery code, which is used up.
+fn check_second_factor(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, AppError> {
+    use crate::schema::{recovery_codes, users};
+    conn.transaction(|conn| {
+        // Lock the user, so that the same code can't be accepted twice by concurrent requests.
+        let (email, enabled, secret, last_step): (String, bool, Option<String>, Option<i64>) =
+            users::table
+                .find(user_id)
+                .select((
+                    users::email,
+                    users::totp_enabled,
+                    users::totp_secret,
+                    users::totp_last_step,
+                ))
+                .for_update()
+                .first(conn)?;
+        if !enabled {
+            return Err(AppError::Conflict(
+                "Two-factor authentication isn't enabled".to_string(),
+            ));
+        }
+        let Some(secret) = secret else {
+            return Ok(false);
+        };
+
+        if let Some(step) = check_totp(&totp(&secret, email)?, code.trim(), last_step) {
+            diesel::update(users::table.find(user_id))
+                .set(users::totp_last_step.eq(step))
+                .execute(conn)?;
+            return Ok(true);
+        }
+
+        let used = diesel::delete(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(user_id))
+                .filter(recovery_codes::code_hash.eq(token::hash(&normalise_recovery_code(code)))),
+        )
+        .execute(conn)?;
+        Ok(used > 0)
+    })
+}
+
+/// Check a second factor of a user, either to finish logging in or to confirm changes to their
+/// two-factor authentication. Wrong codes count as failed logins.
+async fn confirm_second_factor(
+    db: &Db,
+    throttle: &LoginThrottle,
+    client: &ClientInfo,
+    user_id: Uuid,
+    code: String,
+) -> Result<(), AppError> {
+    use crate::schema::users;
+    let email: String = db
+        .run(move |conn| {
+            users::table
+                .find(user_id)
+                .select(users::email)
+                .first(conn)
+                .map_err(DbError::from)
+        })
+        .await?;
+
+    if let Some(wait) = throttle.locked_for(&email, client.ip.as_deref()).await? {
+        return Err(AppError::TooManyAttempts(wait));
+    }
+    let code_correct = db
+        .run(move |conn| check_second_factor(conn, user_id, &code))
+        .await?;
+    if !code_correct {
+        throttle
+            .record_failure(&email, client.ip.as_deref())
+            .await?;
+        return Err(AppError::Unauthorized("Invalid code".to_string()));
+    }
+    throttle.reset(&email).await?;
+    Ok(())
+}
+
+/// Handed out by `/login` instead of a session when the user has two-factor authentication
+/// enabled. The token must be sent to `/login/mfa` along with a code to finish logging in.
+#[derive(Serialize)]
+pub struct MfaChallenge {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    pub expires: NaiveDateTime,
+}
+
+/// Record that a user has given their password, and now needs to give their second factor.
+pub fn create_challenge(conn: &mut PgConnection, user_id: Uuid) -> QueryResult<MfaChallenge> {
+    use crate::schema::mfa_challenges;
+    let now = Utc::now().naive_utc();
+    let mfa_token = token::generate();
+    let expires = now + Duration::minutes(CHALLENGE_MINUTES);
+    diesel::delete(mfa_challenges::table.filter(mfa_challenges::expires.lt(now))).execute(conn)?;
+    diesel::insert_into(mfa_challenges::table)
+        .values((
+            mfa_challenges::token_hash.eq(token::hash(&mfa_token)),
+            mfa_challenges::user_id.eq(user_id),
+            mfa_challenges::expires.eq(expires),
+        ))
+        .execute(conn)?;
+    Ok(MfaChallenge {
+        mfa_required: true,
+        mfa_token,
+        expires,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct MfaLoginBody {
+    mfa_token: String,
+    /// A TOTP code or a recovery code.
+    code: String,
+    /// Whether to hand out the session in cookies rather than in the response body.
+    #[serde(default)]
+    cookie: bool,
+}
+
+/// Second step of logging in for users with two-factor authentication enabled.
+pub async fn login(
+    State(db): State<Db>,
+    State(sessions): State<Sessions>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Json(MfaLoginBody {
+        mfa_token,
+        code,
+        cookie,
+    }): Json<MfaLoginBody>,
+) -> Result<Response, AppError> {
+    use crate::schema::mfa_challenges;
+    let challenge_hash = token::hash(&mfa_token);
+
+    let user_id = {
+        let challenge_hash = challenge_hash.clone();
+        db.run(move |conn| {
+            mfa_challenges::table
+                .find(challenge_hash)
+                .filter(mfa_challenges::expires.gt(Utc::now().naive_utc()))
+                .select(mfa_challenges::user_id)
+                .first::<Uuid>(conn)
+                .optional()
+                .map_err(DbError::from)
+        })
+        .await?
+    };
+    let Some(user_id) = user_id else {
+        return Err(AppError::Unauthorized(
+            "Login expired. Please log in again.".to_string(),
+        ));
+    };
+
+    // Wrong codes count as failed logins, so that guessing them gets the account locked out.
+    confirm_second_factor(&db, &throttle, &client, user_id, code).await?;
+
+    db.run(move |conn| {
+        diesel::delete(mfa_challenges::table.find(challenge_hash)).execute(conn)?;
+        DbResult::Ok(())
+    })
+    .await?;
+    let session = sessions.create(user_id, client).await?;
+
+    Ok(session_response(&sessions, jar, session, cookie))
+}
+
+#[derive(Deserialize)]
+pub struct PasswordBody {
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct Enrolment {
+    /// Base32 secret, for entering into an authenticator app by hand.
+    secret: String,
+    /// `otpauth://` URI, for showing as a QR code.
+    otpauth_uri: String,
+}
+
+/// Start enabling two-factor authentication, by generating a TOTP secret for the user to add to
+/// their authenticator app. It isn't required to log in until confirmed with a code.
+pub async fn enrol(
+    State(db): State<Db>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(PasswordBody { password }): Json<PasswordBody>,
+) -> Result<Json<Enrolment>, AppError> {
+    use crate::schema::users;
+    let user_id = extract_user_id(&headers)?;
+    confirm_password(&db, &throttle, &client, user_id, password).await?;
+    db.run(move |conn| {
+        let (email, enabled): (String, bool) = users::table
+            .find(user_id)
+            .select((users::email, users::totp_enabled))
+            .first(conn)?;
+        if enabled {
+            return Err(AppError::Conflict(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let totp = totp(&secret, email)?;
+        diesel::update(users::table.find(user_id))
+            .set((
+                users::totp_secret.eq(&secret),
+                users::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+
+        Ok(Json(Enrolment {
+            secret,
+            otpauth_uri: totp.get_url(),
+        }))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct CodeBody {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Finish enabling two-factor authentication with a code from the user's authenticator app,
+/// returning recovery codes for if they lose it.
+pub async fn confirm(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Json(CodeBody { code }): Json<CodeBody>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    use crate::schema::users;
+    let user_id = extract_user_id(&headers)?;
+    db.run(move |conn| {
+        let (email, secret, enabled): (String, Option<String>, bool) = users::table
+            .find(user_id)
+            .select((users::email, users::totp_secret, users::totp_enabled))
+            .first(conn)?;
+        if enabled {
+            return Err(AppError::Conflict(
+                "Two-factor authentication is already enabled".to_string(),
+            ));
+        }
+        let Some(secret) = secret else {
+            return Err(AppError::BadRequest(
+                "Two-factor authentication hasn't been set up".to_string(),
+            ));
+        };
+        let Some(step) = check_totp(&totp(&secret, email)?, code.trim(), None) else {
+            return Err(AppError::BadRequest("Invalid code".to_string()));
+        };
+
+        let recovery_codes = conn.transaction(|conn| {
+            diesel::update(users::table.find(user_id))
+                .set((users::totp_enabled.eq(true), users::totp_last_step.eq(step)))
+                .execute(conn)?;
+            new_recovery_codes(conn, user_id)
+        })?;
+        Ok(Json(RecoveryCodes { recovery_codes }))
+    })
+    .await
+}
+
+/// Replace the user's recovery codes, e.g. because they have used most of them.
+pub async fn regenerate_recovery_codes(
+    State(db): State<Db>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(CodeBody { code }): Json<CodeBody>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    let user_id = extract_user_id(&headers)?;
+    confirm_second_factor(&db, &throttle, &client, user_id, code).await?;
+    db.run(move |conn| {
+        let recovery_codes = new_recovery_codes(conn, user_id)?;
+        Ok(Json(RecoveryCodes { recovery_codes }))
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct DisableBody {
+    password: String,
+    code: String,
+}
+
+/// Turn off two-factor authentication, given both the password and a second factor.
+pub async fn disable(
+    State(db): State<Db>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(DisableBody { password, code }): Json<DisableBody>,
+) -> Result<(), AppError> {
+    use crate::schema::{mfa_challenges, recovery_codes, users};
+    let user_id = extract_user_id(&headers)?;
+    confirm_password(&db, &throttle, &client, user_id, password).await?;
+    confirm_second_factor(&db, &throttle, &client, user_id, code).await?;
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            diesel::update(users::table.find(user_id))
+                .set((
+                    users::totp_secret.eq(None::<String>),
+                    users::totp_enabled.eq(false),
+                    users::totp_last_step.eq(None::<i64>),
+                ))
+                .execute(conn)?;
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id)))
+                .execute(conn)?;
+            diesel::delete(mfa_challenges::table.filter(mfa_challenges::user_id.eq(user_id)))
+                .execute(conn)
+        })?;
+        Ok(())
+    })
+    .await
+}
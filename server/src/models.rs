@@ -1,19 +1,63 @@
+use std::{fmt, io::Write, str::FromStr};
+
 use chrono::NaiveDateTime;
-use diesel::prelude::*;
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{self, IsNull, Output, ToSql},
+    sql_types::Text,
+};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::schema::{
-    access_tokens, modules, problem_topic, problems, solutions, topics, user_problem, users,
+    access_tokens, api_keys, modules, problem_topic, problems, sessions, solutions, topics,
+    user_module, user_problem, users,
 };
 
-#[derive(Identifiable, Queryable, Selectable, Debug, Clone)]
+#[derive(Identifiable, Queryable, Selectable, Serialize, Debug, Clone)]
 #[diesel(table_name = access_tokens)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct AccessToken {
     pub id: Uuid,
     pub name: String,
-    pub redeemed: bool,
+    pub max_redemptions: i32,
+    pub redemptions: i32,
+    pub expires: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub created_by: Option<Uuid>,
+    /// Cohort given to users registering with this token.
+    pub cohort: Option<String>,
+    /// Module users registering with this token are enrolled on.
+    pub module_id: Option<i32>,
+}
+
+impl AccessToken {
+    pub fn status(&self, now: NaiveDateTime) -> AccessTokenStatus {
+        if self.revoked_at.is_some() {
+            AccessTokenStatus::Revoked
+        } else if self.redemptions >= self.max_redemptions {
+            AccessTokenStatus::Redeemed
+        } else if self.expires.is_some_and(|expires| expires < now) {
+            AccessTokenStatus::Expired
+        } else {
+            AccessTokenStatus::Active
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTokenStatus {
+    /// The token can still be used to register.
+    Active,
+    /// Every use of the token has been redeemed.
+    Redeemed,
+    Expired,
+    Revoked,
 }
 
 #[derive(Identifiable, Queryable, Selectable, Associations, Serialize, Debug, Clone)]
@@ -52,7 +96,6 @@ pub struct Solution {
 #[diesel(belongs_to(Topic))]
 #[diesel(primary_key(problem_id, topic_id))]
 #[diesel(table_name = problem_topic)]
-#[diesel(primary_key(problem_id, topic_id))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct ProblemTopic {
     pub problem_id: i32,
@@ -63,7 +106,6 @@ pub struct ProblemTopic {
 #[diesel(belongs_to(User))]
 #[diesel(primary_key(user_id, problem_id))]
 #[diesel(table_name = user_problem)]
-#[diesel(primary_key(user_id, problem_id))]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct UserProblem {
     pub user_id: Uuid,
@@ -72,6 +114,17 @@ pub struct UserProblem {
     pub successful: bool,
 }
 
+#[derive(Identifiable, Queryable, Selectable, Insertable, Associations, Debug)]
+#[diesel(belongs_to(Module))]
+#[diesel(belongs_to(User))]
+#[diesel(primary_key(user_id, module_id))]
+#[diesel(table_name = user_module)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserModule {
+    pub user_id: Uuid,
+    pub module_id: i32,
+}
+
 #[derive(Identifiable, Queryable, Selectable, Debug)]
 #[diesel(table_name = users)]
 #[diesel(primary_key(id))]
@@ -81,6 +134,180 @@ pub struct User {
     pub email: String,
     pub id: Uuid,
     pub password: Option<String>,
+    pub role: Role,
+    pub cohort: Option<String>,
+    pub email_verified: bool,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+}
+
+/// What a user is allowed to do. Roles are ordered, so that each role can do everything the roles
+/// below it can.
+#[derive(
+    AsExpression,
+    FromSqlRow,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Student => "student",
+            Self::Moderator => "moderator",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "student" => Ok(Self::Student),
+            "moderator" => Ok(Self::Moderator),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Unknown role: {s}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Role {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Role {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        Ok(std::str::from_utf8(bytes.as_bytes())?.parse()?)
+    }
+}
+
+/// What an API key may be used for.
+#[derive(
+    AsExpression,
+    FromSqlRow,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    /// Only requests which don't change anything, i.e. `GET`, `HEAD` and `OPTIONS`.
+    Read,
+    /// Anything a student can do, such as submitting problems and solutions.
+    Submit,
+    /// Anything the key's owner can do with their role.
+    Admin,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Submit => "submit",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// The role a request made with a key of this scope acts with, for a key owned by a user with
+    /// the role `owner`.
+    pub fn effective_role(&self, owner: Role) -> Role {
+        match self {
+            Self::Read | Self::Submit => owner.min(Role::Student),
+            Self::Admin => owner,
+        }
+    }
+}
+
+impl FromStr for ApiKeyScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "submit" => Ok(Self::Submit),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Unknown API key scope: {s}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for ApiKeyScope {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for ApiKeyScope {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        Ok(std::str::from_utf8(bytes.as_bytes())?.parse()?)
+    }
+}
+
+#[derive(Identifiable, Queryable, Selectable, Associations, Serialize, Debug, Clone)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = api_keys)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub name: String,
+    pub scope: ApiKeyScope,
+    pub created_at: NaiveDateTime,
+    pub last_used: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Identifiable, Queryable, Selectable, Associations, Debug, Clone)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub expires: NaiveDateTime,
+    pub public_id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub absolute_expires: NaiveDateTime,
+    pub refresh_token_hash: Option<String>,
+    pub refresh_expires: Option<NaiveDateTime>,
 }
 
 #[derive(Deserialize)]
@@ -97,10 +324,23 @@ pub enum AddTopic {
     Existing(i32),
 }
 
+/// A topic in any module, which is created if it is new.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum AddTopicIn {
+    Existing(i32),
+    New { module: AddModule, title: String },
+}
+
 #[derive(Deserialize)]
 pub struct NewProblem {
-    pub module: AddModule,
-    pub topic: AddTopic,
+    /// The module of `topic`, which is only needed if it is a new topic.
+    pub module: Option<AddModule>,
+    pub topic: Option<AddTopic>,
+    /// Any other topics the problem belongs to. There must be at least one topic between these and
+    /// `topic`.
+    #[serde(default)]
+    pub topics: Vec<AddTopicIn>,
     pub soln: Option<String>,
     pub soln_img: Option<String>,
     #[serde(flatten)]
@@ -145,3 +385,25 @@ pub struct Topic {
     pub module_id: i32,
     pub title: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_admin_keys_keep_their_owners_role() {
+        for owner in [Role::Student, Role::Moderator, Role::Admin] {
+            assert_eq!(ApiKeyScope::Read.effective_role(owner), Role::Student);
+            assert_eq!(ApiKeyScope::Submit.effective_role(owner), Role::Student);
+            assert_eq!(ApiKeyScope::Admin.effective_role(owner), owner);
+        }
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [ApiKeyScope::Read, ApiKeyScope::Submit, ApiKeyScope::Admin] {
+            assert_eq!(scope.as_str().parse(), Ok(scope));
+        }
+        assert!("write".parse::<ApiKeyScope>().is_err());
+    }
+}
@@ -0,0 +1,957 @@
+use std::{fmt, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Header, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::{
+    auth::set_session_cookies,
+    config,
+    db::{Db, DbError, DbResult},
+    email::{email_in_use, normalise_email},
+    error::AppError,
+    extract::Query,
+    mfa,
+    session::{ClientInfo, Sessions},
+    token,
+};
+
+/// How long a user has to log in at the provider before coming back.
+const LOGIN_MINUTES: i64 = 10;
+
+/// Cookie tying a login to the browser it was started in, holding the hash of its state.
+const STATE_COOKIE: &str = "oidc_state";
+
+/// Format of expiry times handed to the client, matching how they are serialized elsewhere.
+const EXPIRES_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Settings for logging in through an OpenID Connect provider, such as a university's single
+/// sign-on.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Issuer identifier of the provider, used to discover its endpoints.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to, i.e. this server's `/oidc/callback`.
+    pub redirect_uri: String,
+    /// Space-separated scopes to ask for.
+    pub scopes: String,
+    /// Accept ID tokens signed with the client secret (`HS256` and the like), if the provider
+    /// offers them. Only asymmetric signatures are accepted otherwise.
+    pub allow_hmac: bool,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_uri: String::new(),
+            scopes: "openid email profile".to_string(),
+            allow_hmac: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider couldn't be reached, or responded unexpectedly.
+    Provider(String),
+    /// The provider's ID token didn't check out.
+    InvalidToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Provider(e) => write!(f, "Identity provider error: {e}"),
+            Self::InvalidToken(e) => write!(f, "Invalid ID token: {e}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Provider(e.to_string())
+    }
+}
+
+impl From<OidcError> for AppError {
+    fn from(e: OidcError) -> Self {
+        match e {
+            OidcError::Provider(_) => Self::BadGateway(e.to_string()),
+            OidcError::InvalidToken(_) => Self::Unauthorized(e.to_string()),
+        }
+    }
+}
+
+/// The parts of the provider's discovery document which we use.
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: Option<String>,
+    /// How the provider may sign ID tokens. If it doesn't say, this is `RS256`.
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims of an ID token which we use.
+#[derive(Deserialize, Debug)]
+pub struct IdClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+}
+
+/// The PKCE code challenge for a code verifier, using the `S256` method.
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Client for an OpenID Connect provider, using the authorization code flow with PKCE.
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    /// The provider's discovery document, fetched the first time it is needed.
+    async fn metadata(&self) -> Result<&ProviderMetadata, OidcError> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let metadata: ProviderMetadata = self
+                    .client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if metadata.issuer != self.config.issuer {
+                    return Err(OidcError::Provider(format!(
+                        "Discovery document is for issuer {}",
+                        metadata.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    /// Where to send the user to log in at the provider.
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, OidcError> {
+        let metadata = self.metadata().await?;
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", &self.config.scopes),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &pkce_challenge(code_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OidcError::Provider(format!("Invalid authorization endpoint: {e}")))?;
+        Ok(url.into())
+    }
+
+    /// Exchange the authorization code the provider sent the user back with for their verified
+    /// ID token claims.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, OidcError> {
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.config.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let response = self
+            .client
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Provider(format!(
+                "Token request failed with {status}: {body}"
+            )));
+        }
+        let TokenResponse { id_token } = response.json().await?;
+
+        let invalid = |e: jsonwebtoken::errors::Error| OidcError::InvalidToken(e.to_string());
+        let header = jsonwebtoken::decode_header(&id_token).map_err(invalid)?;
+        // The token's own header can't be trusted to say how it should be checked, or else a
+        // token could be signed with whatever key is easiest to forge.
+        if !self.allowed_algorithms(metadata).contains(&header.alg) {
+            return Err(OidcError::InvalidToken(format!(
+                "Signed with {:?}, which isn't allowed",
+                header.alg
+            )));
+        }
+        let key = self.decoding_key(&header).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims: IdClaims = jsonwebtoken::decode(&id_token, &key, &validation)
+            .map_err(invalid)?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidToken("Nonce doesn't match".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// The algorithms ID tokens may be signed with: those the provider says it uses, less HMAC
+    /// unless it is allowed.
+    fn allowed_algorithms(&self, metadata: &ProviderMetadata) -> Vec<Algorithm> {
+        let supported = &metadata.id_token_signing_alg_values_supported;
+        if supported.is_empty() {
+            return vec![Algorithm::RS256];
+        }
+        supported
+            .iter()
+            // Anything jsonwebtoken doesn't know, including `none`, is left out.
+            .filter_map(|alg| alg.parse().ok())
+            .filter(|alg| self.config.allow_hmac || !is_hmac(*alg))
+            .collect()
+    }
+
+    /// The key to check an ID token's signature with: the client secret for HMAC, or otherwise the
+    /// matching key from the provider's JWK set.
+    async fn decoding_key(&self, header: &Header) -> Result<DecodingKey, OidcError> {
+        if is_hmac(header.alg) {
+            let client_secret = self.config.client_secret.as_ref().ok_or_else(|| {
+                OidcError::InvalidToken("HMAC-signed, but there is no client secret".to_string())
+            })?;
+            return Ok(DecodingKey::from_secret(client_secret.as_bytes()));
+        }
+
+        let jwks_uri =
+            self.metadata().await?.jwks_uri.as_ref().ok_or_else(|| {
+                OidcError::Provider("Discovery document has no jwks_uri".to_string())
+            })?;
+        // Fetched every time, rather than cached, so that keys rotated by the provider are picked
+        // up. Logins are rare enough for this not to matter.
+        let jwks: JwkSet = self
+            .client
+            .get(jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| OidcError::InvalidToken("Signed with an unknown key".to_string()))?;
+        DecodingKey::from_jwk(jwk).map_err(|e| OidcError::InvalidToken(e.to_string()))
+    }
+}
+
+fn is_hmac(alg: Algorithm) -> bool {
+    matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+fn enabled(oidc: Option<Arc<Oidc>>) -> Result<Arc<Oidc>, AppError> {
+    oidc.ok_or_else(|| AppError::NotFound("Single sign-on is not configured".to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    /// Whether to hand out the session in cookies when the user comes back.
+    #[serde(default)]
+    cookie: bool,
+}
+
+/// Start logging in through the OpenID Connect provider, by redirecting the user to it.
+pub async fn login(
+    State(db): State<Db>,
+    State(oidc): State<Option<Arc<Oidc>>>,
+    jar: CookieJar,
+    Query(LoginQuery { cookie }): Query<LoginQuery>,
+) -> Result<(CookieJar, Redirect), AppError> {
+    use crate::schema::oidc_logins;
+    let oidc = enabled(oidc)?;
+    let state = token::generate();
+    let nonce = token::generate();
+    let code_verifier = token::generate();
+    let url = oidc
+        .authorization_url(&state, &nonce, &code_verifier)
+        .await?;
+
+    let state_hash = token::hash(&state);
+    // Lax rather than Strict, as it has to be sent along when the provider redirects back.
+    let state_cookie = Cookie::build((STATE_COOKIE, state_hash.clone()))
+        .path("/oidc")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(LOGIN_MINUTES));
+    let now = Utc::now().naive_utc();
+    db.run(move |conn| {
+        diesel::delete(oidc_logins::table.filter(oidc_logins::expires.lt(now))).execute(conn)?;
+        diesel::insert_into(oidc_logins::table)
+            .values((
+                oidc_logins::state_hash.eq(state_hash),
+                oidc_logins::nonce.eq(nonce),
+                oidc_logins::code_verifier.eq(code_verifier),
+                oidc_logins::cookie.eq(cookie),
+                oidc_logins::expires.eq(now + Duration::minutes(LOGIN_MINUTES)),
+            ))
+            .execute(conn)?;
+        DbResult::Ok(())
+    })
+    .await?;
+
+    Ok((jar.add(state_cookie), Redirect::to(&url)))
+}
+
+/// Check that a login is being finished in the browser it was started in. Otherwise, anyone could
+/// start logging in to their own account and have someone else's browser finish it, logging them
+/// in as the attacker without their noticing.
+fn check_state(jar: &CookieJar, state: &str) -> Result<(), AppError> {
+    let invalid =
+        || AppError::BadRequest("Invalid or expired login. Please try again.".to_string());
+    let expected = jar.get(STATE_COOKIE).ok_or_else(invalid)?;
+    let state_hash = token::hash(state);
+    if !bool::from(expected.value().as_bytes().ct_eq(state_hash.as_bytes())) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Where the provider sends the user back to. Starts a session for the user, linking their
+/// identity at the provider to the account with the same (verified) email address, or creating an
+/// account if there is none, and then sends them on to the client.
+///
+/// The client receives the session in the fragment of the URL it is sent to: `session`,
+/// `refresh_token` and `expires`, or `csrf_token` and `expires` if cookies were asked for. Users
+/// with two-factor authentication enabled instead get `mfa_token` and `expires`, to finish logging
+/// in with at `/login/mfa` as they would after giving their password.
+pub async fn callback(
+    State(db): State<Db>,
+    State(oidc): State<Option<Arc<Oidc>>>,
+    State(sessions): State<Sessions>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, AppError> {
+    use crate::schema::oidc_logins;
+    let oidc = enabled(oidc)?;
+    if let Some(error) = query.error {
+        return Err(AppError::Unauthorized(format!(
+            "Login failed: {}",
+            query.error_description.unwrap_or(error)
+        )));
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Err(AppError::BadRequest("Missing code or state".to_string()));
+    };
+    check_state(&jar, &state)?;
+    let jar = jar.remove(Cookie::build(STATE_COOKIE).path("/oidc"));
+
+    let state_hash = token::hash(&state);
+    // Deleting the login as we look it up means each state can only be used once.
+    let (nonce, code_verifier, cookie): (String, String, bool) = db
+        .run(move |conn| {
+            diesel::delete(
+                oidc_logins::table
+                    .find(state_hash)
+                    .filter(oidc_logins::expires.gt(Utc::now().naive_utc())),
+            )
+            .returning((
+                oidc_logins::nonce,
+                oidc_logins::code_verifier,
+                oidc_logins::cookie,
+            ))
+            .get_result(conn)
+            .optional()
+            .map_err(DbError::from)
+        })
+        .await?
+        .ok_or_else(|| {
+            AppError::BadRequest("Invalid or expired login. Please try again.".to_string())
+        })?;
+
+    let claims = oidc.exchange(&code, &code_verifier, &nonce).await?;
+    let issuer = oidc.config.issuer.clone();
+    let (user_id, challenge) = db
+        .run(move |conn| {
+            use crate::schema::users;
+            let user_id = find_or_create_user(conn, &issuer, claims)?;
+            // The provider only stands in for the password, so the second factor is still needed.
+            let totp_enabled: bool = users::table
+                .find(user_id)
+                .select(users::totp_enabled)
+                .first(conn)?;
+            let challenge = totp_enabled
+                .then(|| mfa::create_challenge(conn, user_id))
+                .transpose()?;
+            Ok::<_, AppError>((user_id, challenge))
+        })
+        .await?;
+    let redirect = format!("{}/login/callback", config::get().server.app_url);
+    if let Some(challenge) = challenge {
+        let redirect = format!("{redirect}#{}", mfa_fragment(&challenge));
+        return Ok((jar, Redirect::to(&redirect)).into_response());
+    }
+
+    // XXX: ONLY FROM THIS POINT ON ARE WE AUTHORIZED.
+    let issued = sessions.create(user_id, client).await?;
+    if cookie {
+        let (jar, session) = set_session_cookies(&sessions, jar, issued);
+        let redirect = format!(
+            "{redirect}#csrf_token={}&expires={}",
+            session.csrf_token,
+            session.expires.format(EXPIRES_FORMAT)
+        );
+        Ok((jar, Redirect::to(&redirect)).into_response())
+    } else {
+        let redirect = format!(
+            "{redirect}#session={}&refresh_token={}&expires={}",
+            issued.session,
+            issued.refresh_token,
+            issued.expires.format(EXPIRES_FORMAT)
+        );
+        Ok((jar, Redirect::to(&redirect)).into_response())
+    }
+}
+
+/// What a user with two-factor authentication enabled is sent back to the client with, to give
+/// their second factor with rather than being handed a session.
+fn mfa_fragment(challenge: &mfa::MfaChallenge) -> String {
+    format!(
+        "mfa_token={}&expires={}",
+        challenge.mfa_token,
+        challenge.expires.format(EXPIRES_FORMAT)
+    )
+}
+
+/// The user with an identity at the provider, linking it to an existing account or creating a new
+/// one the first time it is used.
+fn find_or_create_user(
+    conn: &mut PgConnection,
+    issuer: &str,
+    claims: IdClaims,
+) -> Result<Uuid, AppError> {
+    use crate::schema::{user_identities, users};
+    if let Some(user_id) = user_identities::table
+        .find((issuer, &claims.sub))
+        .select(user_identities::user_id)
+        .first(conn)
+        .optional()?
+    {
+        return Ok(user_id);
+    }
+
+    let Some(email) = claims.email.as_deref().map(normalise_email) else {
+        return Err(AppError::Forbidden(
+            "The identity provider didn't give an email address".to_string(),
+        ));
+    };
+    let existing: Option<Uuid> = users::table
+        .filter(users::email.eq(&email))
+        .select(users::id)
+        .first(conn)
+        .optional()?;
+    let user_id = match existing {
+        // Only link to an existing account if the provider vouches for the address, or else anyone
+        // who can make an account there could take over accounts here.
+        Some(user_id) if claims.email_verified => user_id,
+        Some(_) => {
+            return Err(AppError::Conflict(
+                "An account with this email address already exists, and the identity provider \
+             hasn't verified it"
+                    .to_string(),
+            ))
+        }
+        None => diesel::insert_into(users::table)
+            .values((
+                users::name.eq(claims
+                    .name
+                    .or(claims.preferred_username)
+                    .unwrap_or_else(|| email.clone())),
+                users::email.eq(&email),
+                users::email_verified.eq(claims.email_verified),
+            ))
+            .returning(users::id)
+            .get_result(conn)
+            .map_err(email_in_use)?,
+    };
+
+    diesel::insert_into(user_identities::table)
+        .values((
+            user_identities::issuer.eq(issuer),
+            user_identities::subject.eq(&claims.sub),
+            user_identities::user_id.eq(user_id),
+        ))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+    use axum::{
+        extract::Form, http::StatusCode, response::Json, routing::get, routing::post, Router,
+    };
+    use jsonwebtoken::EncodingKey;
+    use openssl::{pkey::Private, rsa::Rsa};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "watson";
+    const CLIENT_SECRET: &str = "client-secret";
+    const REDIRECT_URI: &str = "http://watson.test/oidc/callback";
+    const KEY_ID: &str = "test-key";
+
+    /// A login the mock provider has authorized, waiting for its code to be exchanged.
+    struct Authorization {
+        code_challenge: String,
+        nonce: String,
+    }
+
+    /// A minimal OpenID Connect provider, which authorizes everyone without asking.
+    struct MockProvider {
+        issuer: String,
+        /// Sign ID tokens with this key, or with the client secret if there is none.
+        rsa: Option<Rsa<Private>>,
+        /// Audience of the ID tokens issued.
+        audience: String,
+        /// Signing algorithms given in the discovery document.
+        algorithms: Vec<String>,
+        authorizations: Mutex<HashMap<String, Authorization>>,
+    }
+
+    async fn discovery(State(mock): State<Arc<MockProvider>>) -> Json<Value> {
+        Json(json!({
+            "issuer": mock.issuer,
+            "authorization_endpoint": format!("{}/authorize", mock.issuer),
+            "token_endpoint": format!("{}/token", mock.issuer),
+            "jwks_uri": format!("{}/jwks", mock.issuer),
+            "id_token_signing_alg_values_supported": mock.algorithms,
+        }))
+    }
+
+    async fn authorize(
+        State(mock): State<Arc<MockProvider>>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Redirect {
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+        let code = Uuid::new_v4().to_string();
+        mock.authorizations.lock().unwrap().insert(
+            code.clone(),
+            Authorization {
+                code_challenge: params["code_challenge"].clone(),
+                nonce: params["nonce"].clone(),
+            },
+        );
+        Redirect::to(&format!(
+            "{}?code={code}&state={}",
+            params["redirect_uri"], params["state"]
+        ))
+    }
+
+    async fn issue_token(
+        State(mock): State<Arc<MockProvider>>,
+        Form(params): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        let invalid_grant = || {
+            (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_grant" })),
+            )
+        };
+        assert_eq!(params["grant_type"], "authorization_code");
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["client_secret"], CLIENT_SECRET);
+        let authorization = mock
+            .authorizations
+            .lock()
+            .unwrap()
+            .remove(&params["code"])
+            .ok_or_else(invalid_grant)?;
+        if pkce_challenge(&params["code_verifier"]) != authorization.code_challenge {
+            return Err(invalid_grant());
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "iss": mock.issuer,
+            "sub": "student-1",
+            "aud": mock.audience,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": authorization.nonce,
+            "email": "Student@Uni.test",
+            "email_verified": true,
+            "name": "Test Student",
+        });
+        let id_token = match &mock.rsa {
+            Some(rsa) => {
+                let mut header = Header::new(Algorithm::RS256);
+                header.kid = Some(KEY_ID.to_string());
+                let key = EncodingKey::from_rsa_pem(&rsa.private_key_to_pem().unwrap()).unwrap();
+                jsonwebtoken::encode(&header, &claims, &key)
+            }
+            None => jsonwebtoken::encode(
+                &Header::new(Algorithm::HS256),
+                &claims,
+                &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+            ),
+        }
+        .unwrap();
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    async fn jwks(State(mock): State<Arc<MockProvider>>) -> Json<Value> {
+        let rsa = mock.rsa.as_ref().unwrap();
+        Json(json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": KEY_ID,
+                "alg": "RS256",
+                "use": "sig",
+                "n": URL_SAFE_NO_PAD.encode(rsa.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(rsa.e().to_vec()),
+            }]
+        }))
+    }
+
+    /// Start a mock provider, returning a client for it which accepts the algorithm it signs with.
+    async fn start_provider(rsa: Option<Rsa<Private>>, audience: &str) -> Oidc {
+        let (algorithm, allow_hmac) = match rsa {
+            Some(_) => ("RS256", false),
+            None => ("HS256", true),
+        };
+        start_provider_with(rsa, audience, &[algorithm], allow_hmac).await
+    }
+
+    /// Start a mock provider which says it signs with `algorithms`, returning a client for it.
+    async fn start_provider_with(
+        rsa: Option<Rsa<Private>>,
+        audience: &str,
+        algorithms: &[&str],
+        allow_hmac: bool,
+    ) -> Oidc {
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Arc::new(MockProvider {
+            issuer: issuer.clone(),
+            rsa,
+            audience: audience.to_string(),
+            algorithms: algorithms.iter().map(|alg| alg.to_string()).collect(),
+            authorizations: Mutex::default(),
+        });
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/authorize", get(authorize))
+            .route("/token", post(issue_token))
+            .route("/jwks", get(jwks))
+            .with_state(mock);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Oidc::new(OidcConfig {
+            issuer,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            redirect_uri: REDIRECT_URI.to_string(),
+            scopes: "openid email profile".to_string(),
+            allow_hmac,
+        })
+    }
+
+    /// Follow the authorization URL like a browser would, returning the code the provider sends
+    /// the user back with.
+    async fn authorize_login(oidc: &Oidc, nonce: &str, code_verifier: &str) -> String {
+        let url = oidc
+            .authorization_url("some-state", nonce, code_verifier)
+            .await
+            .unwrap();
+        let response = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .unwrap();
+        let location = response.headers()["location"].to_str().unwrap();
+        let location = reqwest::Url::parse(location).unwrap();
+        assert!(location.as_str().starts_with(REDIRECT_URI));
+        let params: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], "some-state");
+        params["code"].clone()
+    }
+
+    #[tokio::test]
+    async fn login_with_hmac_signed_token() {
+        let oidc = start_provider(None, CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let claims = oidc.exchange(&code, "verifier", "nonce").await.unwrap();
+        assert_eq!(claims.sub, "student-1");
+        assert_eq!(claims.email.as_deref(), Some("Student@Uni.test"));
+        assert!(claims.email_verified);
+        assert_eq!(claims.name.as_deref(), Some("Test Student"));
+    }
+
+    #[tokio::test]
+    async fn login_with_rsa_signed_token() {
+        let oidc = start_provider(Some(Rsa::generate(2048).unwrap()), CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let claims = oidc.exchange(&code, "verifier", "nonce").await.unwrap();
+        assert_eq!(claims.sub, "student-1");
+    }
+
+    #[tokio::test]
+    async fn code_verifier_must_match_challenge() {
+        let oidc = start_provider(None, CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "other-verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::Provider(_))));
+    }
+
+    #[tokio::test]
+    async fn code_can_only_be_used_once() {
+        let oidc = start_provider(None, CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        oidc.exchange(&code, "verifier", "nonce").await.unwrap();
+        let result = oidc.exchange(&code, "verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::Provider(_))));
+    }
+
+    #[tokio::test]
+    async fn nonce_must_match() {
+        let oidc = start_provider(None, CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "verifier", "other-nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn hmac_is_rejected_unless_allowed() {
+        let oidc = start_provider_with(None, CLIENT_ID, &["RS256", "HS256"], false).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn algorithms_the_provider_doesnt_use_are_rejected() {
+        let rsa = Some(Rsa::generate(2048).unwrap());
+        let oidc = start_provider_with(rsa, CLIENT_ID, &["ES256"], false).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn only_rs256_is_accepted_if_the_provider_doesnt_say() {
+        let oidc = start_provider_with(None, CLIENT_ID, &[], true).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidToken(_))));
+
+        let rsa = Some(Rsa::generate(2048).unwrap());
+        let oidc = start_provider_with(rsa, CLIENT_ID, &[], false).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        oidc.exchange(&code, "verifier", "nonce").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn token_for_another_client_is_rejected() {
+        let oidc = start_provider(Some(Rsa::generate(2048).unwrap()), "someone-else").await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+        let result = oidc.exchange(&code, "verifier", "nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn state_must_match_cookie() {
+        let jar = CookieJar::new().add(Cookie::new(STATE_COOKIE, token::hash("some-state")));
+        assert!(check_state(&jar, "some-state").is_ok());
+        assert!(check_state(&jar, "other-state").is_err());
+        // A login started in another browser has no cookie here.
+        assert!(check_state(&CookieJar::new(), "some-state").is_err());
+    }
+
+    /// Runs against the database given by `TEST_DATABASE_URL`, where it enables two-factor
+    /// authentication for the user the mock provider logs in.
+    #[tokio::test]
+    #[ignore = "needs a database, given by TEST_DATABASE_URL"]
+    async fn totp_users_are_sent_to_give_their_second_factor() {
+        use crate::{db::DbConfig, schema::sessions, schema::users};
+        use diesel_migrations::MigrationHarness;
+
+        config::init_default();
+        let db = Db::new(DbConfig {
+            url: std::env::var("TEST_DATABASE_URL").unwrap(),
+            ..DbConfig::default()
+        });
+        db.run(|conn| conn.run_pending_migrations(crate::MIGRATIONS).map(|_| ()))
+            .await
+            .unwrap();
+        let user_id: Uuid = db
+            .run(|conn| {
+                diesel::insert_into(users::table)
+                    .values((
+                        users::name.eq("Test Student"),
+                        users::email.eq("student@uni.test"),
+                        users::email_verified.eq(true),
+                        users::totp_enabled.eq(true),
+                    ))
+                    .on_conflict(users::email)
+                    .do_update()
+                    .set(users::totp_enabled.eq(true))
+                    .returning(users::id)
+                    .get_result(conn)
+                    .map_err(DbError::from)
+            })
+            .await
+            .unwrap();
+
+        // Start the login as `login` would, and have the provider authorize it.
+        let state = token::generate();
+        let state_hash = token::hash(&state);
+        let jar = CookieJar::new().add(Cookie::new(STATE_COOKIE, state_hash.clone()));
+        db.run(move |conn| {
+            use crate::schema::oidc_logins;
+            diesel::insert_into(oidc_logins::table)
+                .values((
+                    oidc_logins::state_hash.eq(state_hash),
+                    oidc_logins::nonce.eq("nonce"),
+                    oidc_logins::code_verifier.eq("verifier"),
+                    oidc_logins::cookie.eq(false),
+                    oidc_logins::expires.eq(Utc::now().naive_utc() + Duration::minutes(1)),
+                ))
+                .execute(conn)
+                .map_err(DbError::from)
+        })
+        .await
+        .unwrap();
+        let oidc = start_provider(None, CLIENT_ID).await;
+        let code = authorize_login(&oidc, "nonce", "verifier").await;
+
+        let sessions_before = count_sessions(&db, user_id).await;
+        let response = callback(
+            State(db.clone()),
+            State(Some(Arc::new(oidc))),
+            State(Sessions::new(db.clone(), config::get().session)),
+            ClientInfo {
+                user_agent: None,
+                ip: None,
+            },
+            jar,
+            Query(CallbackQuery {
+                code: Some(code),
+                state: Some(state),
+                error: None,
+                error_description: None,
+            }),
+        )
+        .await
+        .unwrap();
+
+        let location = response.headers()["location"].to_str().unwrap();
+        let (_, fragment) = location.split_once('#').unwrap();
+        let params: HashMap<_, _> = fragment
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .collect();
+        assert!(params.contains_key("mfa_token"));
+        assert!(params.contains_key("expires"));
+        assert!(!params.contains_key("session"));
+        assert!(!params.contains_key("csrf_token"));
+        assert_eq!(count_sessions(&db, user_id).await, sessions_before);
+
+        async fn count_sessions(db: &Db, user_id: Uuid) -> i64 {
+            db.run(move |conn| {
+                sessions::table
+                    .filter(sessions::user_id.eq(user_id))
+                    .count()
+                    .get_result(conn)
+                    .map_err(DbError::from)
+            })
+            .await
+            .unwrap()
+        }
+    }
+}
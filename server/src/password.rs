@@ -0,0 +1,200 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::HeaderMap};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    auth::{confirm_password, extract_session_id, hash_password},
+    config,
+    db::Db,
+    email::normalise_email,
+    error::AppError,
+    extract::Json,
+    extract_user_id,
+    mail::{self, Email, Mailer},
+    session::ClientInfo,
+    throttle::LoginThrottle,
+    token,
+};
+
+/// How long a password reset link can be used for.
+const RESET_TOKEN_MINUTES: i64 = 60;
+
+#[derive(Deserialize)]
+pub struct ResetRequestBody {
+    email: String,
+}
+
+/// Email a password reset link to a user.
+///
+/// This succeeds whether or not there is a user with the given email address, so that it can't be
+/// used to find out who has an account.
+pub async fn request_reset(
+    State(db): State<Db>,
+    State(mailer): State<Arc<dyn Mailer>>,
+    Json(ResetRequestBody { email }): Json<ResetRequestBody>,
+) -> Result<(), AppError> {
+    use crate::schema::{password_reset_tokens, users};
+    db.run(move |conn| {
+        let email = normalise_email(&email);
+        let Some(user_id) = users::table
+            .filter(users::email.eq(&email))
+            .select(users::id)
+            .first::<Uuid>(conn)
+            .optional()?
+        else {
+            return Ok(());
+        };
+
+        // Only the most recently requested link works.
+        let now = Utc::now().naive_utc();
+        let reset_token = token::generate();
+        conn.transaction(|conn| {
+            diesel::delete(
+                password_reset_tokens::table.filter(
+                    password_reset_tokens::user_id
+                        .eq(user_id)
+                        .or(password_reset_tokens::expires.lt(now)),
+                ),
+            )
+            .execute(conn)?;
+            diesel::insert_into(password_reset_tokens::table)
+                .values((
+                    password_reset_tokens::token_hash.eq(token::hash(&reset_token)),
+                    password_reset_tokens::user_id.eq(user_id),
+                    password_reset_tokens::expires.eq(now + Duration::minutes(RESET_TOKEN_MINUTES)),
+                ))
+                .execute(conn)
+        })?;
+
+        let email = Email {
+            to: email,
+            subject: "Reset your Watson password".to_string(),
+            body: format!(
+                "Someone (hopefully you) asked to reset your Watson password. To choose a new one, \
+                 visit\n\n{}/reset-password?token={reset_token}\n\nThis link expires in \
+                 {RESET_TOKEN_MINUTES} minutes. If you didn't ask to reset your password, you can \
+                 ignore this email.",
+                config::get().server.app_url
+            ),
+        };
+        mail::spawn_send(mailer, email);
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ResetConfirmBody {
+    token: String,
+    password: String,
+}
+
+/// Set a new password using a reset token. The token is used up, and all of the user's sessions
+/// are ended.
+pub async fn confirm_reset(
+    State(db): State<Db>,
+    Json(ResetConfirmBody { token, password }): Json<ResetConfirmBody>,
+) -> Result<(), AppError> {
+    use crate::schema::{password_reset_tokens, sessions, users};
+    db.run(move |conn| {
+        if password.is_empty() {
+            return Err(AppError::BadRequest(
+                "Password must not be empty".to_string(),
+            ));
+        }
+        let hashed_password = hash_password(&password)
+            .map_err(|_| AppError::Internal("Couldn't hash password".to_string()))?;
+
+        let reset = conn.transaction(|conn| {
+            // Deleting the token as we look it up means it can only ever be used once.
+            let Some(user_id) = diesel::delete(
+                password_reset_tokens::table
+                    .find(token::hash(&token))
+                    .filter(password_reset_tokens::expires.gt(Utc::now().naive_utc())),
+            )
+            .returning(password_reset_tokens::user_id)
+            .get_result::<Uuid>(conn)
+            .optional()?
+            else {
+                return Ok(false);
+            };
+
+            // The reset link was emailed to the user, so this also proves that they own their
+            // email address.
+            diesel::update(users::table.find(user_id))
+                .set((
+                    users::password.eq(hashed_password),
+                    users::email_verified.eq(true),
+                ))
+                .execute(conn)?;
+            diesel::delete(
+                password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(sessions::table.filter(sessions::user_id.eq(user_id))).execute(conn)?;
+            diesel::QueryResult::Ok(true)
+        })?;
+
+        if !reset {
+            return Err(AppError::BadRequest(
+                "Invalid or expired reset token".to_string(),
+            ));
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordBody {
+    old_password: String,
+    new_password: String,
+}
+
+/// Change the password of the signed-in user, ending all of their other sessions.
+pub async fn change_password(
+    State(db): State<Db>,
+    State(throttle): State<LoginThrottle>,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Json(ChangePasswordBody {
+        old_password,
+        new_password,
+    }): Json<ChangePasswordBody>,
+) -> Result<(), AppError> {
+    use crate::schema::{password_reset_tokens, sessions, users};
+    let user_id = extract_user_id(&headers)?;
+    let session_id = extract_session_id(&headers)?;
+    confirm_password(&db, &throttle, &client, user_id, old_password).await?;
+    db.run(move |conn| {
+        if new_password.is_empty() {
+            return Err(AppError::BadRequest(
+                "Password must not be empty".to_string(),
+            ));
+        }
+        let hashed_password = hash_password(&new_password)
+            .map_err(|_| AppError::Internal("Couldn't hash password".to_string()))?;
+
+        conn.transaction(|conn| {
+            diesel::update(users::table.find(user_id))
+                .set(users::password.eq(hashed_password))
+                .execute(conn)?;
+            diesel::delete(
+                password_reset_tokens::table.filter(password_reset_tokens::user_id.eq(user_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                sessions::table
+                    .filter(sessions::user_id.eq(user_id))
+                    .filter(sessions::id.ne(session_id)),
+            )
+            .execute(conn)
+        })?;
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,427 @@
+//! Reading, editing and deleting individual problems, and changing their topics.
+
+use axum::{extract::State, http::HeaderMap};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::{Deserialize, Deserializer, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    auth::extract_user_role,
+    db::Db,
+    error::AppError,
+    extract::{Json, Path, Query},
+    extract_user_id,
+    models::{Problem, Role, Solution, Topic},
+    revision::{self, Content},
+};
+
+/// A problem along with everything attached to it.
+#[derive(Serialize)]
+pub struct ProblemDetail {
+    #[serde(flatten)]
+    pub problem: Problem,
+    /// Name of the user who submitted the problem, if they are known.
+    pub submitted_by: Option<String>,
+    pub topics: Vec<Topic>,
+    pub solutions: Vec<Solution>,
+}
+
+/// Load a problem which hasn't been deleted, with its submitter's name, topics and solutions.
+pub fn load_detail(conn: &mut PgConnection, problem_id: i32) -> Result<ProblemDetail, AppError> {
+    use crate::schema::{problems, solutions, users};
+    let (problem, submitted_by) = problems::table
+        .find(problem_id)
+        .filter(problems::deleted_at.is_null())
+        .left_join(users::table)
+        .select((Problem::as_select(), users::name.nullable()))
+        .first(conn)
+        .optional()?
+        .ok_or_else(not_found)?;
+    let topics = load_topics(conn, problem_id)?;
+    let solutions = Solution::belonging_to(&problem)
+        .order(solutions::submitted_at)
+        .select(Solution::as_select())
+        .load(conn)?;
+
+    Ok(ProblemDetail {
+        problem,
+        submitted_by,
+        topics,
+        solutions,
+    })
+}
+
+fn load_topics(conn: &mut PgConnection, problem_id: i32) -> QueryResult<Vec<Topic>> {
+    use crate::schema::{problem_topic, topics};
+    problem_topic::table
+        .inner_join(topics::table)
+        .filter(problem_topic::problem_id.eq(problem_id))
+        .order(topics::id)
+        .select(Topic::as_select())
+        .load(conn)
+}
+
+fn not_found() -> AppError {
+    AppError::NotFound("Problem not found".to_string())
+}
+
+fn fetch(
+    conn: &mut PgConnection,
+    problem_id: i32,
+    include_deleted: bool,
+    lock: bool,
+) -> Result<Problem, AppError> {
+    use crate::schema::problems;
+    if lock {
+        // Boxed queries can't take locks, so the row is locked on its own first.
+        problems::table
+            .find(problem_id)
+            .select(problems::id)
+            .for_update()
+            .first::<i32>(conn)
+            .optional()?;
+    }
+    let mut query = problems::table
+        .find(problem_id)
+        .select(Problem::as_select())
+        .into_boxed();
+    if !include_deleted {
+        query = query.filter(problems::deleted_at.is_null());
+    }
+    query.first(conn).optional()?.ok_or_else(not_found)
+}
+
+/// Find a problem which hasn't been deleted.
+pub fn find(conn: &mut PgConnection, problem_id: i32) -> Result<Problem, AppError> {
+    fetch(conn, problem_id, false, false)
+}
+
+/// Find a problem which hasn't been deleted, and keep it from being changed by anyone else until
+/// the transaction ends.
+pub fn lock(conn: &mut PgConnection, problem_id: i32) -> Result<Problem, AppError> {
+    fetch(conn, problem_id, false, true)
+}
+
+/// Check that the user may change a problem, which only its submitter and moderators can, and lock
+/// it. Deleted problems are treated as missing unless `include_deleted` is set. Returns the
+/// problem's current state.
+fn check_can_edit(
+    conn: &mut PgConnection,
+    problem_id: i32,
+    user_id: Uuid,
+    role: Role,
+    include_deleted: bool,
+) -> Result<Problem, AppError> {
+    let problem = fetch(conn, problem_id, include_deleted, true)?;
+    if problem.user_id != Some(user_id) && role < Role::Moderator {
+        return Err(AppError::Forbidden(
+            "Only the problem's submitter or a moderator can change it".to_string(),
+        ));
+    }
+    Ok(problem)
+}
+
+/// Get a problem by its id.
+pub async fn get_problem(
+    State(db): State<Db>,
+    Path(problem_id): Path<i32>,
+) -> Result<Json<ProblemDetail>, AppError> {
+    db.run(move |conn| load_detail(conn, problem_id))
+        .await
+        .map(Json)
+}
+
+/// Deserialize a field which can be left out, to leave it unchanged, or set to `null`, to clear it.
+pub fn nullable<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Option<String>>, D::Error> {
+    Option::deserialize(d).map(Some)
+}
+
+#[derive(AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::problems)]
+#[serde(deny_unknown_fields)]
+pub struct EditProblem {
+    #[serde(default, deserialize_with = "nullable")]
+    body: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    author: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    source: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    solnlink: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    img_path: Option<Option<String>>,
+}
+
+impl EditProblem {
+    fn is_empty(&self) -> bool {
+        self.body.is_none()
+            && self.author.is_none()
+            && self.source.is_none()
+            && self.solnlink.is_none()
+            && self.img_path.is_none()
+    }
+}
+
+/// Change some of a problem's fields, recording the result as a new revision, and return the
+/// problem as it now is.
+pub async fn edit_problem(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+    Json(edit): Json<EditProblem>,
+) -> Result<Json<ProblemDetail>, AppError> {
+    use crate::schema::problems;
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+    if edit.is_empty() {
+        return Err(AppError::BadRequest("Nothing to change".to_string()));
+    }
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            let previous = check_can_edit(conn, problem_id, user_id, role, false)?;
+            let problem: Problem = diesel::update(problems::table.find(problem_id))
+                .set(&edit)
+                .returning(Problem::as_returning())
+                .get_result(conn)?;
+            if problem.body.is_none() && problem.img_path.is_none() {
+                return Err(AppError::BadRequest(
+                    "A problem must have a body or an image".to_string(),
+                ));
+            }
+            if !problem.same_content(&previous) {
+                revision::record_problem(conn, &problem, Some(user_id))?;
+            }
+            load_detail(conn, problem_id)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct DeleteOptions {
+    /// Remove the problem and its solutions for good, rather than just hiding them. Only
+    /// moderators can do this.
+    #[serde(default)]
+    hard: bool,
+}
+
+/// Delete a problem. By default it is only hidden, so that it can be restored.
+pub async fn delete_problem(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+    Query(DeleteOptions { hard }): Query<DeleteOptions>,
+) -> Result<(), AppError> {
+    use crate::schema::{problems, solutions};
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+    if hard && role < Role::Moderator {
+        return Err(AppError::Forbidden(
+            "Only moderators can permanently delete problems".to_string(),
+        ));
+    }
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            // Moderators can permanently delete problems which were already hidden.
+            check_can_edit(conn, problem_id, user_id, role, hard)?;
+            if hard {
+                // Topics and attempts are removed along with the problem, but solutions aren't.
+                diesel::delete(solutions::table.filter(solutions::problem_id.eq(problem_id)))
+                    .execute(conn)?;
+                diesel::delete(problems::table.find(problem_id)).execute(conn)?;
+            } else {
+                diesel::update(problems::table.find(problem_id))
+                    .set(problems::deleted_at.eq(Utc::now().naive_utc()))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    })
+    .await
+}
+
+/// Restore a deleted problem, which only moderators can do.
+pub async fn restore_problem(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+) -> Result<Json<ProblemDetail>, AppError> {
+    use crate::schema::problems;
+    if extract_user_role(&headers)? < Role::Moderator {
+        return Err(AppError::Forbidden(
+            "Only moderators can restore problems".to_string(),
+        ));
+    }
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            fetch(conn, problem_id, true, true)?;
+            let restored = diesel::update(
+                problems::table
+                    .find(problem_id)
+                    .filter(problems::deleted_at.is_not_null()),
+            )
+            .set(problems::deleted_at.eq(None::<NaiveDateTime>))
+            .execute(conn)?;
+            if restored == 0 {
+                return Err(AppError::Conflict(
+                    "The problem hasn't been deleted".to_string(),
+                ));
+            }
+            load_detail(conn, problem_id)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+/// Check that every topic exists, returning their ids without duplicates.
+pub fn check_topics_exist(
+    conn: &mut PgConnection,
+    topic_ids: &[i32],
+) -> Result<Vec<i32>, AppError> {
+    use crate::schema::topics;
+    let mut topic_ids = topic_ids.to_vec();
+    topic_ids.sort_unstable();
+    topic_ids.dedup();
+    let found: Vec<i32> = topics::table
+        .filter(topics::id.eq_any(&topic_ids))
+        .select(topics::id)
+        .load(conn)?;
+    let missing: Vec<String> = topic_ids
+        .iter()
+        .filter(|id| !found.contains(id))
+        .map(i32::to_string)
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::Unprocessable(format!(
+            "No topics with ids {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(topic_ids)
+}
+
+/// Add topics to a problem, leaving any it already has.
+pub fn attach_topics(
+    conn: &mut PgConnection,
+    problem_id: i32,
+    topic_ids: &[i32],
+) -> QueryResult<()> {
+    use crate::schema::problem_topic;
+    diesel::insert_into(problem_topic::table)
+        .values(
+            topic_ids
+                .iter()
+                .map(|&topic_id| {
+                    (
+                        problem_topic::problem_id.eq(problem_id),
+                        problem_topic::topic_id.eq(topic_id),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopicIds {
+    topic_ids: Vec<i32>,
+}
+
+/// Add topics to a problem, returning all of its topics.
+pub async fn add_topics(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+    Json(TopicIds { topic_ids }): Json<TopicIds>,
+) -> Result<Json<Vec<Topic>>, AppError> {
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            check_can_edit(conn, problem_id, user_id, role, false)?;
+            let topic_ids = check_topics_exist(conn, &topic_ids)?;
+            attach_topics(conn, problem_id, &topic_ids)?;
+            Ok(load_topics(conn, problem_id)?)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+/// Replace all of a problem's topics, returning them.
+pub async fn set_topics(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+    Json(TopicIds { topic_ids }): Json<TopicIds>,
+) -> Result<Json<Vec<Topic>>, AppError> {
+    use crate::schema::problem_topic;
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+    if topic_ids.is_empty() {
+        return Err(AppError::BadRequest(
+            "A problem must have at least one topic".to_string(),
+        ));
+    }
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            check_can_edit(conn, problem_id, user_id, role, false)?;
+            let topic_ids = check_topics_exist(conn, &topic_ids)?;
+            diesel::delete(
+                problem_topic::table
+                    .filter(problem_topic::problem_id.eq(problem_id))
+                    .filter(problem_topic::topic_id.ne_all(&topic_ids)),
+            )
+            .execute(conn)?;
+            attach_topics(conn, problem_id, &topic_ids)?;
+            Ok(load_topics(conn, problem_id)?)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+/// Remove a topic from a problem, returning the topics it has left. Its last topic can't be
+/// removed, as it could then never be served.
+pub async fn remove_topic(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path((problem_id, topic_id)): Path<(i32, i32)>,
+) -> Result<Json<Vec<Topic>>, AppError> {
+    use crate::schema::problem_topic;
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            check_can_edit(conn, problem_id, user_id, role, false)?;
+            let removed =
+                diesel::delete(problem_topic::table.find((problem_id, topic_id))).execute(conn)?;
+            if removed == 0 {
+                return Err(AppError::NotFound(
+                    "The problem doesn't have that topic".to_string(),
+                ));
+            }
+            let topics = load_topics(conn, problem_id)?;
+            if topics.is_empty() {
+                return Err(AppError::BadRequest(
+                    "A problem must have at least one topic".to_string(),
+                ));
+            }
+            Ok(topics)
+        })
+    })
+    .await
+    .map(Json)
+}
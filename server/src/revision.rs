@@ -0,0 +1,411 @@
+//! Revision history for problems and solutions. Every version of each is kept from when it was
+//! created, so that edits can be reviewed, compared and, by moderators, undone.
+
+use axum::{extract::State, http::HeaderMap};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+use uuid::Uuid;
+
+use crate::{
+    auth::extract_user_role,
+    db::Db,
+    error::AppError,
+    extract::{Json, Path, Query},
+    extract_user_id,
+    models::{Problem, Role, Solution},
+    problem::{self, ProblemDetail},
+    schema::{problem_revisions, solution_revisions},
+    solution,
+};
+
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = problem_revisions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ProblemRevision {
+    pub problem_id: i32,
+    pub revision: i32,
+    pub body: Option<String>,
+    pub author: Option<String>,
+    pub source: Option<String>,
+    pub solnlink: Option<String>,
+    pub img_path: Option<String>,
+    pub edited_by: Option<Uuid>,
+    pub edited_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Serialize)]
+#[diesel(table_name = solution_revisions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct SolutionRevision {
+    pub solution_id: i32,
+    pub revision: i32,
+    pub body: Option<String>,
+    pub img_path: Option<String>,
+    pub edited_by: Option<Uuid>,
+    pub edited_at: NaiveDateTime,
+}
+
+/// The fields of a problem or solution which are kept in its revisions.
+pub trait Content {
+    fn fields(&self) -> Vec<(&'static str, Option<&str>)>;
+
+    fn same_content(&self, other: &impl Content) -> bool {
+        self.fields() == other.fields()
+    }
+}
+
+/// Implement [`Content`] for a live row and its revisions from the one list of fields, so that the
+/// two can't disagree about what is kept.
+macro_rules! impl_content {
+    ($live:ty, $revision:ty => [$($field:ident),+]) => {
+        impl Content for $live {
+            fn fields(&self) -> Vec<(&'static str, Option<&str>)> {
+                vec![$((stringify!($field), self.$field.as_deref())),+]
+            }
+        }
+
+        impl Content for $revision {
+            fn fields(&self) -> Vec<(&'static str, Option<&str>)> {
+                vec![$((stringify!($field), self.$field.as_deref())),+]
+            }
+        }
+    };
+}
+
+impl_content!(Problem, ProblemRevision => [body, author, source, solnlink, img_path]);
+impl_content!(Solution, SolutionRevision => [body, img_path]);
+
+/// Record a problem's content as its latest revision. The problem must be locked, or have just been
+/// created, so that two revisions can't be given the same number.
+pub fn record_problem(
+    conn: &mut PgConnection,
+    problem: &Problem,
+    editor: Option<Uuid>,
+) -> QueryResult<()> {
+    let latest: Option<i32> = problem_revisions::table
+        .filter(problem_revisions::problem_id.eq(problem.id))
+        .select(diesel::dsl::max(problem_revisions::revision))
+        .first(conn)?;
+    diesel::insert_into(problem_revisions::table)
+        .values((
+            problem_revisions::problem_id.eq(problem.id),
+            problem_revisions::revision.eq(latest.unwrap_or(0) + 1),
+            problem_revisions::body.eq(&problem.body),
+            problem_revisions::author.eq(&problem.author),
+            problem_revisions::source.eq(&problem.source),
+            problem_revisions::solnlink.eq(&problem.solnlink),
+            problem_revisions::img_path.eq(&problem.img_path),
+            problem_revisions::edited_by.eq(editor),
+            problem_revisions::edited_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Record a solution's content as its latest revision, under the same conditions as
+/// [`record_problem`].
+pub fn record_solution(
+    conn: &mut PgConnection,
+    solution: &Solution,
+    editor: Option<Uuid>,
+) -> QueryResult<()> {
+    let latest: Option<i32> = solution_revisions::table
+        .filter(solution_revisions::solution_id.eq(solution.id))
+        .select(diesel::dsl::max(solution_revisions::revision))
+        .first(conn)?;
+    diesel::insert_into(solution_revisions::table)
+        .values((
+            solution_revisions::solution_id.eq(solution.id),
+            solution_revisions::revision.eq(latest.unwrap_or(0) + 1),
+            solution_revisions::body.eq(&solution.body),
+            solution_revisions::img_path.eq(&solution.img_path),
+            solution_revisions::edited_by.eq(editor),
+            solution_revisions::edited_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn revision_not_found() -> AppError {
+    AppError::NotFound("Revision not found".to_string())
+}
+
+fn check_moderator(headers: &HeaderMap) -> Result<Uuid, AppError> {
+    let user_id = extract_user_id(headers)?;
+    if extract_user_role(headers)? < Role::Moderator {
+        return Err(AppError::Forbidden(
+            "Only moderators can roll back changes".to_string(),
+        ));
+    }
+    Ok(user_id)
+}
+
+/// A revision, with the name of the user who made it.
+#[derive(Serialize)]
+pub struct Listed<R> {
+    #[serde(flatten)]
+    revision: R,
+    editor: Option<String>,
+}
+
+/// The revisions of a problem, oldest first.
+pub async fn list_problem_revisions(
+    State(db): State<Db>,
+    Path(problem_id): Path<i32>,
+) -> Result<Json<Vec<Listed<ProblemRevision>>>, AppError> {
+    use crate::schema::users;
+    db.run(move |conn| {
+        problem::find(conn, problem_id)?;
+        let revisions = problem_revisions::table
+            .filter(problem_revisions::problem_id.eq(problem_id))
+            .left_join(users::table)
+            .order(problem_revisions::revision)
+            .select((ProblemRevision::as_select(), users::name.nullable()))
+            .load(conn)?;
+        Ok(revisions
+            .into_iter()
+            .map(|(revision, editor)| Listed { revision, editor })
+            .collect())
+    })
+    .await
+    .map(Json)
+}
+
+/// The revisions of a solution, oldest first.
+pub async fn list_solution_revisions(
+    State(db): State<Db>,
+    Path(solution_id): Path<i32>,
+) -> Result<Json<Vec<Listed<SolutionRevision>>>, AppError> {
+    use crate::schema::users;
+    db.run(move |conn| {
+        solution::find(conn, solution_id)?;
+        let revisions = solution_revisions::table
+            .filter(solution_revisions::solution_id.eq(solution_id))
+            .left_join(users::table)
+            .order(solution_revisions::revision)
+            .select((SolutionRevision::as_select(), users::name.nullable()))
+            .load(conn)?;
+        Ok(revisions
+            .into_iter()
+            .map(|(revision, editor)| Listed { revision, editor })
+            .collect())
+    })
+    .await
+    .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct DiffOptions {
+    from: i32,
+    /// Defaults to the latest revision.
+    to: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct Diff {
+    from: i32,
+    to: i32,
+    /// Only the fields which differ between the two revisions.
+    fields: Vec<FieldDiff>,
+}
+
+#[derive(Serialize)]
+pub struct FieldDiff {
+    field: &'static str,
+    changes: Vec<Change>,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    op: Op,
+    text: String,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Equal,
+    Insert,
+    Delete,
+}
+
+/// Compare two versions word by word. A missing field is compared as if it were empty.
+fn diff(from: &impl Content, to: &impl Content) -> Vec<FieldDiff> {
+    from.fields()
+        .into_iter()
+        .zip(to.fields())
+        .filter(|((_, old), (_, new))| old != new)
+        .map(|((field, old), (_, new))| {
+            let mut changes: Vec<Change> = Vec::new();
+            let diff = TextDiff::from_words(old.unwrap_or(""), new.unwrap_or(""));
+            for change in diff.iter_all_changes() {
+                let op = match change.tag() {
+                    ChangeTag::Equal => Op::Equal,
+                    ChangeTag::Insert => Op::Insert,
+                    ChangeTag::Delete => Op::Delete,
+                };
+                match changes.last_mut() {
+                    Some(last) if last.op == op => last.text.push_str(change.value()),
+                    _ => changes.push(Change {
+                        op,
+                        text: change.value().to_string(),
+                    }),
+                }
+            }
+            FieldDiff { field, changes }
+        })
+        .collect()
+}
+
+fn load_problem_revision(
+    conn: &mut PgConnection,
+    problem_id: i32,
+    revision: Option<i32>,
+) -> Result<ProblemRevision, AppError> {
+    let mut query = problem_revisions::table
+        .filter(problem_revisions::problem_id.eq(problem_id))
+        .into_boxed();
+    query = match revision {
+        Some(revision) => query.filter(problem_revisions::revision.eq(revision)),
+        None => query.order(problem_revisions::revision.desc()),
+    };
+    query
+        .select(ProblemRevision::as_select())
+        .first(conn)
+        .optional()?
+        .ok_or_else(revision_not_found)
+}
+
+fn load_solution_revision(
+    conn: &mut PgConnection,
+    solution_id: i32,
+    revision: Option<i32>,
+) -> Result<SolutionRevision, AppError> {
+    let mut query = solution_revisions::table
+        .filter(solution_revisions::solution_id.eq(solution_id))
+        .into_boxed();
+    query = match revision {
+        Some(revision) => query.filter(solution_revisions::revision.eq(revision)),
+        None => query.order(solution_revisions::revision.desc()),
+    };
+    query
+        .select(SolutionRevision::as_select())
+        .first(conn)
+        .optional()?
+        .ok_or_else(revision_not_found)
+}
+
+/// The changes made to a problem between two of its revisions.
+pub async fn diff_problem(
+    State(db): State<Db>,
+    Path(problem_id): Path<i32>,
+    Query(DiffOptions { from, to }): Query<DiffOptions>,
+) -> Result<Json<Diff>, AppError> {
+    db.run(move |conn| {
+        problem::find(conn, problem_id)?;
+        let from = load_problem_revision(conn, problem_id, Some(from))?;
+        let to = load_problem_revision(conn, problem_id, to)?;
+        Ok(Diff {
+            from: from.revision,
+            to: to.revision,
+            fields: diff(&from, &to),
+        })
+    })
+    .await
+    .map(Json)
+}
+
+/// The changes made to a solution between two of its revisions.
+pub async fn diff_solution(
+    State(db): State<Db>,
+    Path(solution_id): Path<i32>,
+    Query(DiffOptions { from, to }): Query<DiffOptions>,
+) -> Result<Json<Diff>, AppError> {
+    db.run(move |conn| {
+        solution::find(conn, solution_id)?;
+        let from = load_solution_revision(conn, solution_id, Some(from))?;
+        let to = load_solution_revision(conn, solution_id, to)?;
+        Ok(Diff {
+            from: from.revision,
+            to: to.revision,
+            fields: diff(&from, &to),
+        })
+    })
+    .await
+    .map(Json)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rollback {
+    revision: i32,
+}
+
+/// Restore a problem's content to that of an earlier revision. This is recorded as a new revision,
+/// so it can itself be undone.
+pub async fn rollback_problem(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(problem_id): Path<i32>,
+    Json(Rollback { revision }): Json<Rollback>,
+) -> Result<Json<ProblemDetail>, AppError> {
+    use crate::schema::problems;
+    let user_id = check_moderator(&headers)?;
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            let current = problem::lock(conn, problem_id)?;
+            let target = load_problem_revision(conn, problem_id, Some(revision))?;
+            if !current.same_content(&target) {
+                let problem: Problem = diesel::update(problems::table.find(problem_id))
+                    .set((
+                        problems::body.eq(&target.body),
+                        problems::author.eq(&target.author),
+                        problems::source.eq(&target.source),
+                        problems::solnlink.eq(&target.solnlink),
+                        problems::img_path.eq(&target.img_path),
+                    ))
+                    .returning(Problem::as_returning())
+                    .get_result(conn)?;
+                record_problem(conn, &problem, Some(user_id))?;
+            }
+            problem::load_detail(conn, problem_id)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+/// Restore a solution's content to that of an earlier revision, as for problems.
+pub async fn rollback_solution(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(solution_id): Path<i32>,
+    Json(Rollback { revision }): Json<Rollback>,
+) -> Result<Json<Solution>, AppError> {
+    use crate::schema::solutions;
+    let user_id = check_moderator(&headers)?;
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            let current = solution::lock(conn, solution_id)?;
+            let target = load_solution_revision(conn, solution_id, Some(revision))?;
+            if current.same_content(&target) {
+                return Ok(current);
+            }
+            let solution: Solution = diesel::update(solutions::table.find(solution_id))
+                .set((
+                    solutions::body.eq(&target.body),
+                    solutions::img_path.eq(&target.img_path),
+                ))
+                .returning(Solution::as_returning())
+                .get_result(conn)?;
+            record_solution(conn, &solution, Some(user_id))?;
+            Ok(solution)
+        })
+    })
+    .await
+    .map(Json)
+}
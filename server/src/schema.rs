@@ -1,10 +1,72 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+}
+
 diesel::table! {
     access_tokens (id) {
         id -> Uuid,
         name -> Varchar,
-        redeemed -> Bool,
+        max_redemptions -> Int4,
+        redemptions -> Int4,
+        expires -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        created_by -> Nullable<Uuid>,
+        cohort -> Nullable<Varchar>,
+        module_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    api_keys (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        key_hash -> Varchar,
+        scope -> Varchar,
+        created_at -> Timestamp,
+        last_used -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    email_verification_tokens (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        email -> Varchar,
+        expires -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_failure_sources (email, ip) {
+        email -> Varchar,
+        ip -> Varchar,
+        last_failure -> Timestamp,
+    }
+}
+
+diesel::table! {
+    login_throttles (scope, key) {
+        scope -> Varchar,
+        key -> Varchar,
+        failures -> Int4,
+        last_failure -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    mfa_challenges (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        expires -> Timestamp,
     }
 }
 
@@ -15,6 +77,39 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    oidc_logins (state_hash) {
+        state_hash -> Varchar,
+        nonce -> Varchar,
+        code_verifier -> Varchar,
+        cookie -> Bool,
+        expires -> Timestamp,
+    }
+}
+
+diesel::table! {
+    password_reset_tokens (token_hash) {
+        token_hash -> Varchar,
+        user_id -> Uuid,
+        expires -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    problem_revisions (problem_id, revision) {
+        problem_id -> Int4,
+        revision -> Int4,
+        body -> Nullable<Text>,
+        author -> Nullable<Varchar>,
+        source -> Nullable<Varchar>,
+        solnlink -> Nullable<Varchar>,
+        img_path -> Nullable<Varchar>,
+        edited_by -> Nullable<Uuid>,
+        edited_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     problem_topic (problem_id, topic_id) {
         problem_id -> Int4,
@@ -23,6 +118,9 @@ diesel::table! {
 }
 
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Tsvector;
+
     problems (id) {
         id -> Int4,
         body -> Nullable<Text>,
@@ -32,6 +130,42 @@ diesel::table! {
         submitted_at -> Timestamp,
         user_id -> Nullable<Uuid>,
         img_path -> Nullable<Varchar>,
+        deleted_at -> Nullable<Timestamp>,
+        search -> Tsvector,
+    }
+}
+
+diesel::table! {
+    recovery_codes (user_id, code_hash) {
+        user_id -> Uuid,
+        code_hash -> Varchar,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        expires -> Timestamp,
+        public_id -> Uuid,
+        created_at -> Timestamp,
+        last_seen -> Timestamp,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        absolute_expires -> Timestamp,
+        refresh_token_hash -> Nullable<Varchar>,
+        refresh_expires -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    solution_revisions (solution_id, revision) {
+        solution_id -> Int4,
+        revision -> Int4,
+        body -> Nullable<Text>,
+        img_path -> Nullable<Varchar>,
+        edited_by -> Nullable<Uuid>,
+        edited_at -> Timestamp,
     }
 }
 
@@ -55,6 +189,22 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_identities (issuer, subject) {
+        issuer -> Varchar,
+        subject -> Varchar,
+        user_id -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_module (user_id, module_id) {
+        user_id -> Uuid,
+        module_id -> Int4,
+    }
+}
+
 diesel::table! {
     user_problem (user_id, problem_id) {
         user_id -> Uuid,
@@ -70,25 +220,59 @@ diesel::table! {
         email -> Varchar,
         id -> Uuid,
         password -> Nullable<Varchar>,
+        role -> Varchar,
+        cohort -> Nullable<Varchar>,
+        email_verified -> Bool,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        totp_last_step -> Nullable<Int8>,
     }
 }
 
+diesel::joinable!(access_tokens -> modules (module_id));
+diesel::joinable!(access_tokens -> users (created_by));
+diesel::joinable!(api_keys -> users (user_id));
+diesel::joinable!(email_verification_tokens -> users (user_id));
+diesel::joinable!(mfa_challenges -> users (user_id));
+diesel::joinable!(password_reset_tokens -> users (user_id));
+diesel::joinable!(problem_revisions -> problems (problem_id));
+diesel::joinable!(problem_revisions -> users (edited_by));
 diesel::joinable!(problem_topic -> problems (problem_id));
 diesel::joinable!(problem_topic -> topics (topic_id));
 diesel::joinable!(problems -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(solution_revisions -> solutions (solution_id));
+diesel::joinable!(solution_revisions -> users (edited_by));
 diesel::joinable!(solutions -> problems (problem_id));
 diesel::joinable!(solutions -> users (user_id));
 diesel::joinable!(topics -> modules (module_id));
+diesel::joinable!(user_identities -> users (user_id));
+diesel::joinable!(user_module -> modules (module_id));
+diesel::joinable!(user_module -> users (user_id));
 diesel::joinable!(user_problem -> problems (problem_id));
 diesel::joinable!(user_problem -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     access_tokens,
+    api_keys,
+    email_verification_tokens,
+    login_failure_sources,
+    login_throttles,
+    mfa_challenges,
     modules,
+    oidc_logins,
+    password_reset_tokens,
+    problem_revisions,
     problem_topic,
     problems,
+    recovery_codes,
+    sessions,
+    solution_revisions,
     solutions,
     topics,
+    user_identities,
+    user_module,
     user_problem,
     users,
 );
@@ -0,0 +1,143 @@
+//! Full-text search over problems, their sources and their solutions. The index itself is kept up
+//! to date by the database whenever any of these change.
+
+use axum::extract::State;
+use diesel::{
+    pg::Pg,
+    prelude::*,
+    sql_types::{Integer, Nullable, Text},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    browse::{self, comma_separated, DEFAULT_LIMIT, MAX_LIMIT},
+    db::Db,
+    error::AppError,
+    extract::{Json, Query},
+    models::{Problem, Topic},
+    schema::sql_types::Tsvector,
+};
+
+#[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+#[diesel(postgres_type(name = "tsquery", schema = "pg_catalog"))]
+pub struct Tsquery;
+
+sql_function! {
+    /// Parse a search as written by a user, in the same way as a web search engine.
+    fn search_query(query: Text) -> Tsquery;
+}
+sql_function! {
+    fn ts_rank_cd(vector: Tsvector, query: Tsquery) -> Float;
+}
+sql_function! {
+    /// Fragments of a problem around what `query` matched, from its body, its source or a solution,
+    /// as HTML with the matches in `<mark>` tags.
+    fn search_headline(
+        body: Nullable<Text>,
+        source: Nullable<Text>,
+        problem_id: Integer,
+        query: Tsquery,
+    ) -> Nullable<Text>;
+}
+diesel::infix_operator!(Matches, " @@ ", backend: Pg);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchOptions {
+    q: String,
+    /// Only problems with a topic in this module.
+    module: Option<i32>,
+    /// Only problems with any of these topics.
+    #[serde(default, deserialize_with = "comma_separated")]
+    topics: Option<Vec<i32>>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    problem: Problem,
+    topics: Vec<Topic>,
+    /// How well the problem matched, relative to the other results.
+    rank: f32,
+    /// The parts of the body, source or a solution which matched, as HTML.
+    headline: Option<String>,
+}
+
+/// Search problems, best matches first.
+pub async fn search(
+    State(db): State<Db>,
+    Query(options): Query<SearchOptions>,
+) -> Result<Json<Vec<SearchResult>>, AppError> {
+    use crate::schema::{problem_topic, problems, topics};
+    let limit = options.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(AppError::BadRequest(format!(
+            "The limit must be between 1 and {MAX_LIMIT}"
+        )));
+    }
+    if options.offset < 0 {
+        return Err(AppError::BadRequest(
+            "The offset can't be negative".to_string(),
+        ));
+    }
+    if options.q.trim().is_empty() {
+        return Err(AppError::BadRequest("Nothing to search for".to_string()));
+    }
+
+    db.run(move |conn| {
+        let query = || search_query(options.q.clone());
+        let mut results = problems::table
+            .filter(problems::deleted_at.is_null())
+            .filter(Matches::new(problems::search, query()))
+            .select((
+                Problem::as_select(),
+                ts_rank_cd(problems::search, query()),
+                search_headline(problems::body, problems::source, problems::id, query()),
+            ))
+            .into_boxed();
+        if let Some(module_id) = options.module {
+            results = results.filter(
+                problems::id.eq_any(
+                    problem_topic::table
+                        .inner_join(topics::table)
+                        .filter(topics::module_id.eq(module_id))
+                        .select(problem_topic::problem_id),
+                ),
+            );
+        }
+        if let Some(topic_ids) = options.topics {
+            results = results.filter(
+                problems::id.eq_any(
+                    problem_topic::table
+                        .filter(problem_topic::topic_id.eq_any(topic_ids))
+                        .select(problem_topic::problem_id),
+                ),
+            );
+        }
+        let results: Vec<(Problem, f32, Option<String>)> = results
+            .order((
+                ts_rank_cd(problems::search, query()).desc(),
+                problems::id.desc(),
+            ))
+            .limit(limit)
+            .offset(options.offset)
+            .load(conn)?;
+
+        let ids: Vec<i32> = results.iter().map(|(problem, _, _)| problem.id).collect();
+        let mut topics = browse::topics_of(conn, &ids)?;
+        Ok(results
+            .into_iter()
+            .map(|(problem, rank, headline)| SearchResult {
+                topics: topics.remove(&problem.id).unwrap_or_default(),
+                problem,
+                rank,
+                headline,
+            })
+            .collect())
+    })
+    .await
+    .map(Json)
+}
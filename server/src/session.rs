@@ -0,0 +1,333 @@
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    db::{Db, DbError, DbResult},
+    models::{Role, Session},
+    token,
+};
+
+/// How often expired sessions are removed from the database.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How stale (in seconds) `last_seen` may get before it is refreshed, so that we don't write to the
+/// database on every single request.
+const LAST_SEEN_RESOLUTION: i64 = 60;
+
+/// How long sessions and refresh tokens last.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// A session expires after being unused for this long.
+    #[serde(rename = "idle_minutes", deserialize_with = "crate::config::minutes")]
+    pub idle_timeout: chrono::Duration,
+    /// A session expires this long after it was started (or refreshed), however active it is.
+    #[serde(
+        rename = "absolute_minutes",
+        deserialize_with = "crate::config::minutes"
+    )]
+    pub absolute_timeout: chrono::Duration,
+    /// How long a refresh token can be used to start new sessions after logging in.
+    #[serde(
+        rename = "refresh_token_days",
+        deserialize_with = "crate::config::days"
+    )]
+    pub refresh_timeout: chrono::Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: chrono::Duration::minutes(2 * 60),
+            absolute_timeout: chrono::Duration::minutes(24 * 60),
+            refresh_timeout: chrono::Duration::days(30),
+        }
+    }
+}
+
+/// The credentials handed to a client when a session is started.
+#[derive(Serialize, Debug)]
+pub struct IssuedSession {
+    pub session: Uuid,
+    pub refresh_token: String,
+    pub expires: NaiveDateTime,
+}
+
+/// Details about the client a session is being started from.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            user_agent: parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .map(str::to_string),
+            ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        })
+    }
+}
+
+/// Session store backed by the `sessions` table.
+///
+/// Keeping sessions in the database rather than in memory means they survive restarts and can be
+/// shared between several instances of the server.
+#[derive(Clone)]
+pub struct Sessions {
+    db: Db,
+    config: SessionConfig,
+}
+
+impl Sessions {
+    pub fn new(db: Db, config: SessionConfig) -> Self {
+        Self { db, config }
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Start a new session for the given user.
+    pub async fn create(&self, user_id: Uuid, client: ClientInfo) -> DbResult<IssuedSession> {
+        use crate::schema::sessions;
+        let config = self.config;
+        let now = Utc::now().naive_utc();
+        let refresh_token = token::generate();
+        let refresh_token_hash = token::hash(&refresh_token);
+        let session = self
+            .db
+            .run(move |conn| {
+                diesel::insert_into(sessions::table)
+                    .values((
+                        sessions::id.eq(Uuid::new_v4()),
+                        sessions::public_id.eq(Uuid::new_v4()),
+                        sessions::user_id.eq(user_id),
+                        sessions::expires.eq(now + config.idle_timeout),
+                        sessions::absolute_expires.eq(now + config.absolute_timeout),
+                        sessions::created_at.eq(now),
+                        sessions::last_seen.eq(now),
+                        sessions::user_agent.eq(client.user_agent),
+                        sessions::ip.eq(client.ip),
+                        sessions::refresh_token_hash.eq(refresh_token_hash),
+                        sessions::refresh_expires.eq(now + config.refresh_timeout),
+                    ))
+                    .returning(Session::as_returning())
+                    .get_result(conn)
+                    .map_err(DbError::from)
+            })
+            .await?;
+
+        Ok(IssuedSession {
+            session: session.id,
+            refresh_token,
+            expires: session.expires,
+        })
+    }
+
+    /// Exchange a refresh token for a new session id and refresh token.
+    ///
+    /// The session keeps its public id, so it still appears as the same session to the user, but
+    /// both the old session id and the old refresh token stop working. Returns `None` if the
+    /// refresh token is unknown or has expired.
+    pub async fn refresh(
+        &self,
+        refresh_token: &str,
+        client: ClientInfo,
+    ) -> DbResult<Option<IssuedSession>> {
+        use crate::schema::sessions;
+        let config = self.config;
+        let now = Utc::now().naive_utc();
+        let old_hash = token::hash(refresh_token);
+        let new_refresh_token = token::generate();
+        let new_hash = token::hash(&new_refresh_token);
+        let session = self
+            .db
+            .run(move |conn| {
+                diesel::update(
+                    sessions::table
+                        .filter(sessions::refresh_token_hash.eq(old_hash))
+                        .filter(sessions::refresh_expires.gt(now)),
+                )
+                .set((
+                    sessions::id.eq(Uuid::new_v4()),
+                    sessions::expires.eq(now + config.idle_timeout),
+                    sessions::absolute_expires.eq(now + config.absolute_timeout),
+                    sessions::last_seen.eq(now),
+                    sessions::user_agent.eq(client.user_agent),
+                    sessions::ip.eq(client.ip),
+                    sessions::refresh_token_hash.eq(new_hash),
+                ))
+                .returning(Session::as_returning())
+                .get_result(conn)
+                .optional()
+                .map_err(DbError::from)
+            })
+            .await?;
+
+        Ok(session.map(|session| IssuedSession {
+            session: session.id,
+            refresh_token: new_refresh_token,
+            expires: session.expires,
+        }))
+    }
+
+    /// Look up a session by its id, alongside the role of the user it belongs to. Note that the
+    /// returned session may have expired.
+    pub async fn get(&self, id: Uuid) -> DbResult<Option<(Session, Role)>> {
+        use crate::schema::{sessions, users};
+        self.db
+            .run(move |conn| {
+                sessions::table
+                    .find(id)
+                    .inner_join(users::table)
+                    .select((Session::as_select(), users::role))
+                    .first(conn)
+                    .optional()
+                    .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// All sessions belonging to a user which are active or can still be refreshed, most recently
+    /// used first.
+    pub async fn list_for_user(&self, user_id: Uuid) -> DbResult<Vec<Session>> {
+        use crate::schema::sessions;
+        let now = Utc::now().naive_utc();
+        self.db
+            .run(move |conn| {
+                sessions::table
+                    .filter(sessions::user_id.eq(user_id))
+                    .filter(
+                        sessions::expires
+                            .gt(now)
+                            .or(sessions::refresh_expires.gt(now)),
+                    )
+                    .order(sessions::last_seen.desc())
+                    .select(Session::as_select())
+                    .load(conn)
+                    .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// Record that the session has just been used, pushing back its expiry (up to its absolute
+    /// lifetime).
+    pub async fn touch(&self, session: &Session) -> DbResult<()> {
+        use crate::schema::sessions;
+        let now = Utc::now().naive_utc();
+        if now.signed_duration_since(session.last_seen).num_seconds() < LAST_SEEN_RESOLUTION {
+            return Ok(());
+        }
+
+        let id = session.id;
+        let expires = (now + self.config.idle_timeout).min(session.absolute_expires);
+        self.db
+            .run(move |conn| {
+                diesel::update(sessions::table.find(id))
+                    .set((sessions::last_seen.eq(now), sessions::expires.eq(expires)))
+                    .execute(conn)?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// How many sessions haven't expired.
+    pub async fn count_active(&self) -> DbResult<i64> {
+        use crate::schema::sessions;
+        let now = Utc::now().naive_utc();
+        self.db
+            .run(move |conn| {
+                sessions::table
+                    .filter(sessions::expires.gt(now))
+                    .count()
+                    .get_result(conn)
+                    .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// End a session. Returns whether the session existed.
+    pub async fn delete(&self, id: Uuid) -> DbResult<bool> {
+        use crate::schema::sessions;
+        self.db
+            .run(move |conn| {
+                diesel::delete(sessions::table.find(id))
+                    .execute(conn)
+                    .map(|n| n > 0)
+                    .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// End one of a user's sessions by its public id. Returns whether such a session existed.
+    pub async fn delete_for_user(&self, user_id: Uuid, public_id: Uuid) -> DbResult<bool> {
+        use crate::schema::sessions;
+        self.db
+            .run(move |conn| {
+                diesel::delete(
+                    sessions::table
+                        .filter(sessions::user_id.eq(user_id))
+                        .filter(sessions::public_id.eq(public_id)),
+                )
+                .execute(conn)
+                .map(|n| n > 0)
+                .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// Delete every session which has expired and can no longer be refreshed, returning how many
+    /// were removed.
+    pub async fn purge_expired(&self) -> DbResult<usize> {
+        use crate::schema::sessions;
+        let now = Utc::now().naive_utc();
+        self.db
+            .run(move |conn| {
+                diesel::delete(
+                    sessions::table.filter(sessions::expires.lt(now)).filter(
+                        sessions::refresh_expires
+                            .is_null()
+                            .or(sessions::refresh_expires.lt(now)),
+                    ),
+                )
+                .execute(conn)
+                .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// Periodically purge expired sessions in the background.
+    pub fn spawn_purge_task(&self) -> JoinHandle<()> {
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = sessions.purge_expired().await {
+                    tracing::error!("Failed to purge expired sessions: {e}");
+                }
+            }
+        })
+    }
+}
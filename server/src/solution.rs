@@ -0,0 +1,153 @@
+//! Editing solutions to problems.
+
+use axum::{extract::State, http::HeaderMap};
+use diesel::{pg::PgConnection, prelude::*};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    auth::extract_user_role,
+    db::Db,
+    error::AppError,
+    extract::{Json, Path},
+    extract_user_id,
+    models::{Role, Solution},
+    problem::nullable,
+    revision::{self, Content},
+};
+
+fn fetch(conn: &mut PgConnection, solution_id: i32, lock: bool) -> Result<Solution, AppError> {
+    use crate::schema::{problems, solutions};
+    if lock {
+        // Taking the lock first means the solution read below is its latest version.
+        solutions::table
+            .find(solution_id)
+            .select(solutions::id)
+            .for_update()
+            .first::<i32>(conn)
+            .optional()?;
+    }
+    // Solutions to deleted problems are hidden along with them.
+    solutions::table
+        .find(solution_id)
+        .filter(
+            solutions::problem_id.eq_any(
+                problems::table
+                    .filter(problems::deleted_at.is_null())
+                    .select(problems::id),
+            ),
+        )
+        .select(Solution::as_select())
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFound("Solution not found".to_string()))
+}
+
+/// Find a solution to a problem which hasn't been deleted.
+pub fn find(conn: &mut PgConnection, solution_id: i32) -> Result<Solution, AppError> {
+    fetch(conn, solution_id, false)
+}
+
+/// Find a solution as with [`find`], and keep it from being changed by anyone else until the
+/// transaction ends.
+pub fn lock(conn: &mut PgConnection, solution_id: i32) -> Result<Solution, AppError> {
+    fetch(conn, solution_id, true)
+}
+
+/// Check that the user may change a solution, which only its submitter and moderators can.
+fn check_can_edit(solution: &Solution, user_id: Uuid, role: Role) -> Result<(), AppError> {
+    if solution.user_id != Some(user_id) && role < Role::Moderator {
+        return Err(AppError::Forbidden(
+            "Only the solution's submitter or a moderator can change it".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::solutions)]
+#[serde(deny_unknown_fields)]
+pub struct EditSolution {
+    #[serde(default, deserialize_with = "nullable")]
+    body: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    img_path: Option<Option<String>>,
+}
+
+/// Change a solution, which only its submitter and moderators can do, recording the result as a
+/// new revision.
+pub async fn edit_solution(
+    State(db): State<Db>,
+    headers: HeaderMap,
+    Path(solution_id): Path<i32>,
+    Json(edit): Json<EditSolution>,
+) -> Result<Json<Solution>, AppError> {
+    use crate::schema::solutions;
+    let user_id = extract_user_id(&headers)?;
+    let role = extract_user_role(&headers)?;
+    if edit.body.is_none() && edit.img_path.is_none() {
+        return Err(AppError::BadRequest("Nothing to change".to_string()));
+    }
+
+    db.run(move |conn| {
+        conn.transaction(|conn| {
+            let previous = lock(conn, solution_id)?;
+            check_can_edit(&previous, user_id, role)?;
+            let solution: Solution = diesel::update(solutions::table.find(solution_id))
+                .set(&edit)
+                .returning(Solution::as_returning())
+                .get_result(conn)?;
+            if solution.body.is_none() && solution.img_path.is_none() {
+                return Err(AppError::BadRequest(
+                    "A solution must have a body or an image".to_string(),
+                ));
+            }
+            if !solution.same_content(&previous) {
+                revision::record_solution(conn, &solution, Some(user_id))?;
+            }
+            Ok(solution)
+        })
+    })
+    .await
+    .map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solution(user_id: Option<Uuid>) -> Solution {
+        Solution {
+            id: 1,
+            problem_id: 1,
+            body: Some("1 + 1 = 2".to_string()),
+            submitted_at: Default::default(),
+            user_id,
+            img_path: None,
+        }
+    }
+
+    #[test]
+    fn only_submitters_and_moderators_can_edit() {
+        let author = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let solution = solution(Some(author));
+        assert!(check_can_edit(&solution, author, Role::Student).is_ok());
+        assert!(matches!(
+            check_can_edit(&solution, other, Role::Student),
+            Err(AppError::Forbidden(_))
+        ));
+        assert!(check_can_edit(&solution, other, Role::Moderator).is_ok());
+        assert!(check_can_edit(&solution, other, Role::Admin).is_ok());
+    }
+
+    #[test]
+    fn solutions_without_a_submitter_are_left_to_moderators() {
+        let solution = solution(None);
+        assert!(matches!(
+            check_can_edit(&solution, Uuid::new_v4(), Role::Student),
+            Err(AppError::Forbidden(_))
+        ));
+        assert!(check_can_edit(&solution, Uuid::new_v4(), Role::Moderator).is_ok());
+    }
+}
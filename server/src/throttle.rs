@@ -0,0 +1,342 @@
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+
+use crate::db::{Db, DbError, DbResult};
+
+/// How often stale failed attempts are removed from the database.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long the first lockout lasts. Each further failure doubles it.
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+
+/// Failed attempts are forgotten once there have been none for this long.
+const FAILURE_MEMORY_HOURS: i64 = 24;
+
+/// What failed login attempts are counted against.
+#[derive(Clone, Copy, Debug)]
+enum Scope {
+    /// The email address being logged in as, whether or not it belongs to anyone. Counting
+    /// unknown addresses too means lockouts don't give away who has an account.
+    Email,
+    /// The IP address of the client, to slow down guessing across many accounts.
+    Ip,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Email => "email",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+/// How quickly clients are locked out after failing to log in.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Failed attempts allowed for an email address before it is locked out.
+    #[serde(rename = "failures_before_lockout")]
+    pub email_failures: i32,
+    /// Failed attempts allowed from an IP address before it is locked out.
+    #[serde(rename = "ip_failures_before_lockout")]
+    pub ip_failures: i32,
+    /// The longest a lockout can last.
+    #[serde(
+        rename = "max_lockout_minutes",
+        deserialize_with = "crate::config::minutes"
+    )]
+    pub max_lockout: chrono::Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            email_failures: 5,
+            ip_failures: 50,
+            max_lockout: chrono::Duration::minutes(60),
+        }
+    }
+}
+
+/// Tracks failed login attempts in the `login_throttles` table, locking out email addresses and
+/// IP addresses for exponentially longer after repeated failures.
+#[derive(Clone)]
+pub struct LoginThrottle {
+    db: Db,
+    config: ThrottleConfig,
+}
+
+impl LoginThrottle {
+    pub fn new(db: Db, config: ThrottleConfig) -> Self {
+        Self { db, config }
+    }
+
+    /// How long until a client at `ip` may try to log in as `email`, if either is locked out.
+    pub async fn locked_for(
+        &self,
+        email: &str,
+        ip: Option<&str>,
+    ) -> DbResult<Option<chrono::Duration>> {
+        use crate::schema::login_throttles;
+        let now = Utc::now().naive_utc();
+        let email = email.to_string();
+        let ip = ip.unwrap_or_default().to_string();
+        let locked_until: Option<NaiveDateTime> = self
+            .db
+            .run(move |conn| {
+                let scope_is = |scope: Scope, key: String| {
+                    login_throttles::scope
+                        .eq(scope.as_str())
+                        .and(login_throttles::key.eq(key))
+                };
+                login_throttles::table
+                    .filter(scope_is(Scope::Email, email).or(scope_is(Scope::Ip, ip)))
+                    .filter(login_throttles::locked_until.gt(now))
+                    .select(diesel::dsl::max(login_throttles::locked_until))
+                    .first(conn)
+                    .map_err(DbError::from)
+            })
+            .await?;
+        Ok(locked_until.map(|until| until - now))
+    }
+
+    /// Count a failed attempt to log in as `email` from `ip`.
+    pub async fn record_failure(&self, email: &str, ip: Option<&str>) -> DbResult<()> {
+        let config = self.config;
+        let email = email.to_string();
+        let ip = ip.map(str::to_string);
+        self.db
+            .run(move |conn| {
+                conn.transaction(|conn| {
+                    record(conn, &config, Scope::Email, &email, config.email_failures)?;
+                    if let Some(ip) = ip {
+                        record(conn, &config, Scope::Ip, &ip, config.ip_failures)?;
+                        record_source(conn, &email, &ip)?;
+                    }
+                    Ok(())
+                })
+            })
+            .await
+    }
+
+    /// Forget the failed attempts to log in as `email`, e.g. after a successful login. Returns
+    /// whether there were any.
+    ///
+    /// Failures from the client's IP address are kept, so that an attacker can't clear them by
+    /// logging in to their own account.
+    pub async fn reset(&self, email: &str) -> DbResult<bool> {
+        use crate::schema::login_throttles;
+        let email = email.to_string();
+        self.db
+            .run(move |conn| {
+                diesel::delete(login_throttles::table.find((Scope::Email.as_str(), email)))
+                    .execute(conn)
+                    .map(|n| n > 0)
+                    .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// Lift the lockout of `email`, for an admin. Lockouts of IP addresses which failed to log in as
+    /// `email` are lifted too, unless they also failed against other addresses, as they could then
+    /// be guessing passwords across accounts.
+    pub async fn unlock(&self, email: &str) -> DbResult<Unlocked> {
+        use crate::schema::{login_failure_sources, login_throttles};
+        let now = Utc::now().naive_utc();
+        let email = email.to_string();
+        self.db
+            .run(move |conn| {
+                conn.transaction(|conn| {
+                    let email_unlocked = diesel::delete(
+                        login_throttles::table.find((Scope::Email.as_str(), &email)),
+                    )
+                    .execute(conn)?
+                        > 0;
+                    let ips: Vec<String> = diesel::delete(
+                        login_failure_sources::table
+                            .filter(login_failure_sources::email.eq(&email)),
+                    )
+                    .returning(login_failure_sources::ip)
+                    .get_results(conn)?;
+
+                    let mut unlocked = Unlocked {
+                        email: email_unlocked,
+                        ips: Vec::new(),
+                        ips_still_locked: Vec::new(),
+                    };
+                    for ip in ips {
+                        let shared: bool = diesel::select(diesel::dsl::exists(
+                            login_failure_sources::table.filter(login_failure_sources::ip.eq(&ip)),
+                        ))
+                        .get_result(conn)?;
+                        let throttle = login_throttles::table.find((Scope::Ip.as_str(), &ip));
+                        if !shared {
+                            if diesel::delete(throttle).execute(conn)? > 0 {
+                                unlocked.ips.push(ip);
+                            }
+                        } else if throttle
+                            .filter(login_throttles::locked_until.gt(now))
+                            .count()
+                            .get_result::<i64>(conn)?
+                            > 0
+                        {
+                            unlocked.ips_still_locked.push(ip);
+                        }
+                    }
+                    Ok(unlocked)
+                })
+            })
+            .await
+    }
+
+    /// Delete every record of failed attempts which is no longer locked out or remembered,
+    /// returning how many were removed.
+    pub async fn purge_stale(&self) -> DbResult<usize> {
+        use crate::schema::{login_failure_sources, login_throttles};
+        let now = Utc::now().naive_utc();
+        let forget_before = now - chrono::Duration::hours(FAILURE_MEMORY_HOURS);
+        self.db
+            .run(move |conn| {
+                diesel::delete(
+                    login_failure_sources::table
+                        .filter(login_failure_sources::last_failure.lt(forget_before)),
+                )
+                .execute(conn)?;
+                diesel::delete(
+                    login_throttles::table
+                        .filter(login_throttles::last_failure.lt(forget_before))
+                        .filter(
+                            login_throttles::locked_until
+                                .is_null()
+                                .or(login_throttles::locked_until.lt(now)),
+                        ),
+                )
+                .execute(conn)
+                .map_err(DbError::from)
+            })
+            .await
+    }
+
+    /// Periodically purge stale failed attempts in the background.
+    pub fn spawn_purge_task(&self) -> JoinHandle<()> {
+        let throttle = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = throttle.purge_stale().await {
+                    tracing::error!("Failed to purge stale login attempts: {e}");
+                }
+            }
+        })
+    }
+}
+
+/// What an admin's unlock lifted.
+#[derive(Serialize, Debug)]
+pub struct Unlocked {
+    /// Whether the email address had failed attempts.
+    pub email: bool,
+    /// IP addresses whose failures were forgotten, as they had only failed against this address.
+    pub ips: Vec<String>,
+    /// IP addresses which failed against this address and are still locked out, as they also
+    /// failed against others.
+    pub ips_still_locked: Vec<String>,
+}
+
+/// Remember that `ip` failed to log in as `email`.
+fn record_source(conn: &mut PgConnection, email: &str, ip: &str) -> QueryResult<()> {
+    use crate::schema::login_failure_sources;
+    let now = Utc::now().naive_utc();
+    diesel::insert_into(login_failure_sources::table)
+        .values((
+            login_failure_sources::email.eq(email),
+            login_failure_sources::ip.eq(ip),
+            login_failure_sources::last_failure.eq(now),
+        ))
+        .on_conflict((login_failure_sources::email, login_failure_sources::ip))
+        .do_update()
+        .set(login_failure_sources::last_failure.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Count a failed attempt against `key`, locking it out if it has now failed too many times.
+fn record(
+    conn: &mut PgConnection,
+    config: &ThrottleConfig,
+    scope: Scope,
+    key: &str,
+    allowed_failures: i32,
+) -> QueryResult<()> {
+    use crate::schema::login_throttles;
+    let now = Utc::now().naive_utc();
+    let previous: Option<(i32, NaiveDateTime)> = login_throttles::table
+        .find((scope.as_str(), key))
+        .select((login_throttles::failures, login_throttles::last_failure))
+        .for_update()
+        .first(conn)
+        .optional()?;
+    let failures = match previous {
+        Some((failures, last_failure))
+            if now - last_failure < chrono::Duration::hours(FAILURE_MEMORY_HOURS) =>
+        {
+            failures + 1
+        }
+        _ => 1,
+    };
+    let locked_until = lockout(failures, allowed_failures, config.max_lockout).map(|d| now + d);
+
+    diesel::insert_into(login_throttles::table)
+        .values((
+            login_throttles::scope.eq(scope.as_str()),
+            login_throttles::key.eq(key),
+            login_throttles::failures.eq(failures),
+            login_throttles::last_failure.eq(now),
+            login_throttles::locked_until.eq(locked_until),
+        ))
+        .on_conflict((login_throttles::scope, login_throttles::key))
+        .do_update()
+        .set((
+            login_throttles::failures.eq(failures),
+            login_throttles::last_failure.eq(now),
+            login_throttles::locked_until.eq(locked_until),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// How long a key is locked out for after `failures` failed attempts in a row, if at all.
+fn lockout(
+    failures: i32,
+    allowed_failures: i32,
+    max_lockout: chrono::Duration,
+) -> Option<chrono::Duration> {
+    (failures >= allowed_failures).then(|| {
+        let doublings = (failures - allowed_failures).min(30) as u32;
+        chrono::Duration::seconds(BASE_LOCKOUT_SECONDS << doublings).min(max_lockout)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let max = chrono::Duration::minutes(60);
+        let lockout = |failures| lockout(failures, 5, max).map(|d| d.num_seconds());
+        assert_eq!(lockout(1), None);
+        assert_eq!(lockout(4), None);
+        assert_eq!(lockout(5), Some(30));
+        assert_eq!(lockout(6), Some(60));
+        assert_eq!(lockout(8), Some(240));
+        assert_eq!(lockout(12), Some(3600));
+        assert_eq!(lockout(1000), Some(3600));
+    }
+}